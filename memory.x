@@ -5,4 +5,7 @@ MEMORY
     CCRAM : ORIGIN = 0x10000000, LENGTH = 10K
 }
 
-_stack_start = ORIGIN(CCRAM) + LENGTH(CCRAM);
\ No newline at end of file
+_stack_start = ORIGIN(CCRAM) + LENGTH(CCRAM);
+/* The stack has all of CCRAM, below RAM, so cortex-m-rt's default end in RAM
+   would be above its start. */
+_stack_end = ORIGIN(CCRAM);
@@ -1,191 +1,597 @@
 #![allow(dead_code)]
 
-use stm32g4xx_hal::stm32::{ADC1, ADC2, ADC12_COMMON};
-use stm32g4xx_hal::stm32::adc1::smpr1::SMP0_A::{Cycles245};
-use stm32g4xx_hal::stm32::adc1::smpr2::SMP16_A::{Cycles25};
-use stm32g4xx_hal::stm32::adc1::smpr2::SMP18_A::{Cycles2475};
-use stm32g4xx_hal::stm32::adc1::jsqr::{JEXTSEL_A, JEXTEN_A};
-use stm32g4xx_hal::stm32::adc1::cfgr::{OVRMOD_A, EXTSEL_A, EXTEN_A};
+use core::ops::Deref;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use stm32g4xx_hal::stm32::{adc1, ADC1, ADC2, ADC12_COMMON};
 use stm32g4xx_hal::stm32::adc12_common::ccr::{DUAL_A};
+use cortex_m::peripheral::DWT;
+use ufoc::sense::{oversampled_full_scale, PeriodCounter, Sense};
+use ufoc::sense::temperature::InternalSensor;
+
+use crate::dma::{DMAChannel, DMAMux};
+
+/// Highest ADC input channel number.
+pub const MAX_CHANNEL: u8 = 18;
+/// Internal reference voltage channel, on ADC1 only.
+const VREFINT_CHANNEL: u8 = 18;
+/// Internal temperature sensor channel, on ADC1 only. Needs at least 5us of
+/// sampling time.
+pub const TEMP_SENSOR_CHANNEL: u8 = 16;
+
+/// Sampling time in ADC clock cycles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleTime {
+    Cycles2_5,
+    Cycles6_5,
+    Cycles12_5,
+    Cycles24_5,
+    Cycles47_5,
+    Cycles92_5,
+    Cycles247_5,
+    Cycles640_5,
+}
 
-pub struct Adc1 {
-    adc: ADC1,
-    vref_cal: f32,
+/// External trigger edge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
 }
 
-pub struct Adc2 {
-    adc: ADC2,
-    vref_cal: f32,
+impl Edge {
+    const fn bits(self) -> u32 {
+        match self {
+            Edge::Rising => 0b01,
+            Edge::Falling => 0b10,
+            Edge::Both => 0b11,
+        }
+    }
+}
+
+/// External trigger sources for regular conversions used on this board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegularTrigger {
+    Tim1Cc1,
+    Tim1Cc2,
+    Tim1Cc3,
+    Tim1Trgo,
+    Tim1Trgo2,
 }
 
-impl Adc1 {
-    pub fn new(adc: ADC1) -> Self {
-        Self { adc, vref_cal: 0.0 }
+impl RegularTrigger {
+    /// EXTSEL value for ADC1/ADC2.
+    const fn bits(self) -> u32 {
+        match self {
+            RegularTrigger::Tim1Cc1 => 0,
+            RegularTrigger::Tim1Cc2 => 1,
+            RegularTrigger::Tim1Cc3 => 2,
+            RegularTrigger::Tim1Trgo => 9,
+            RegularTrigger::Tim1Trgo2 => 10,
+        }
     }
+}
 
-    pub fn setup(&mut self, adc12: ADC12_COMMON) {
-        self.adc.cr.modify(|_, w| w.deeppwd().clear_bit());
+/// External trigger sources for injected conversions used on this board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InjectedTrigger {
+    Tim1Trgo,
+    Tim1Cc4,
+    Tim1Trgo2,
+}
 
-        adc12.ccr.modify(|_, w| w.dual().variant(DUAL_A::DualRj));
+impl InjectedTrigger {
+    /// JEXTSEL value for ADC1/ADC2.
+    const fn bits(self) -> u32 {
+        match self {
+            InjectedTrigger::Tim1Trgo => 0,
+            InjectedTrigger::Tim1Cc4 => 1,
+            InjectedTrigger::Tim1Trgo2 => 8,
+        }
+    }
+}
 
-        adc12.ccr.modify(|_, w| w.vrefen().set_bit().dmacfg().set_bit());
-        self.adc.cr.modify(|_, w| w.advregen().enabled());
-        while self.adc.cr.read().advregen().bit_is_clear() {} // Wait for the avrgen
+/// DMA requests for regular conversions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmaMode {
+    Disabled,
+    /// Stop requesting when the DMA transfer count runs out.
+    OneShot,
+    /// Keep requesting, for a circular DMA channel.
+    Circular,
+}
 
-        cortex_m::asm::delay(300_000);
+/// ADC interrupt sources, by IER bit position.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    EndOfConversion = 2,
+    EndOfSequence = 3,
+    Overrun = 4,
+    InjectedEndOfConversion = 5,
+    InjectedEndOfSequence = 6,
+    AnalogWatchdog1 = 7,
+    AnalogWatchdog2 = 8,
+    AnalogWatchdog3 = 9,
+}
 
-        self.adc.cr.modify(|_, w| w.adcal().set_bit().adcaldif().single_ended());
-        while self.adc.cr.read().adcal().bit_is_set() {} // Wait for the calibration
+/// Analog watchdogs. AWD1 has 12-bit thresholds, AWD2 and AWD3 compare
+/// only the 8 most significant bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watchdog {
+    Awd1,
+    Awd2,
+    Awd3,
+}
 
-        self.calc_vref();
+impl Watchdog {
+    const ALL: [Watchdog; 3] = [Watchdog::Awd1, Watchdog::Awd2, Watchdog::Awd3];
 
-        self.adc.cfgr.write(|w| w.jqdis().disabled().dmaen().enabled().dmacfg().circular());
-        self.adc.cfgr2.write(|w| unsafe {w.bits(0)});
+    /// Flag position in ISR and IER.
+    const fn bit(self) -> u32 {
+        7 + self as u32
+    }
 
-        //set sampling time for I_A and Temp
-        self.adc.smpr1.write(|w| w.smp3().variant(Cycles245));
-        self.adc.smpr2.write(|w| w.smp13().variant(Cycles25));
+    /// Widest threshold value.
+    const fn max_threshold(self) -> u32 {
+        match self {
+            Watchdog::Awd1 => 0xFFF,
+            Watchdog::Awd2 | Watchdog::Awd3 => 0xFF,
+        }
+    }
+}
 
-        //set  regular sequence to just Temp
-        self.adc.sqr1.write(|w| w.l().variant(7).sq1().variant(3).sq2().variant(3)
-            .sq3().variant(3).sq4().variant(3));
-        self.adc.sqr2.write(|w| w.sq5().variant(3).sq6().variant(3)
-            .sq7().variant(3).sq8().variant(3));
+/// An analog watchdog saw a conversion outside its window.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchdogEvent {
+    /// ADC number, 1 or 2.
+    pub adc: u8,
+    pub watchdog: Watchdog,
+    /// Input channel guarded by the watchdog.
+    pub channel: u8,
+}
 
-        // Set current injected channels
-        self.adc.jsqr.write(|w| w.jl().variant(1).jextsel().variant(JEXTSEL_A::Tim1Trgo)
-            .jexten().variant(JEXTEN_A::RisingEdge).jsq1().variant(13).jsq2().variant(13));
+/// Number of conversions accumulated into one oversampled result.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OversampleRatio {
+    X2,
+    X4,
+    X8,
+    X16,
+    X32,
+    X64,
+    X128,
+    X256,
+}
 
-        self.adc.cfgr.modify(|_, w| w.ovrmod().overwrite()
-            .extsel().variant(EXTSEL_A::Tim1Trgo2).exten().variant(EXTEN_A::RisingEdge));
+/// Hardware oversampling: `ratio` conversions summed and the sum shifted
+/// right by `shift` bits, with rounding.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Oversampling {
+    ratio: OversampleRatio,
+    shift: u8,
+}
 
-        self.adc.ier.modify(|_, w| w.jeosie().enabled());
+impl Oversampling {
+    /// The result must fit the 16-bit data registers, so at most four bits
+    /// of the sum may be kept beyond the 12 of a single conversion.
+    pub const fn new(ratio: OversampleRatio, shift: u8) -> Self {
+        assert!(shift <= 8, "Oversampling shift is 0 to 8 bits");
+        let ratio_log2 = ratio as u32 + 1;
+        assert!(ratio_log2 <= shift as u32 + 4, "Oversampled result exceeds 16 bits");
+        Self { ratio, shift }
+    }
 
+    /// Full-scale reading of an oversampled result.
+    pub const fn full_scale(self) -> f32 {
+        oversampled_full_scale(self.ratio as u32 + 1, self.shift as u32)
     }
-    
-    fn calc_vref(&mut self) {
-        const VREFINTCAL_MIN : u16 = 1570;
-        const VREFINTCAL_MAX : u16 = 1734;
-        const ADC_FAC_CAL_VOL : f32 = 3.0;
-        const FLT_MAXCNT : f32 = 4095.0;
-        const VREFINT_CAL_DEF : f32 = 1.212;
-        const ADC_SMPLS : u16 = 128;
 
-        let temp_smpr2 = self.adc.smpr2.read().bits();
-        let temp_sqr1 = self.adc.sqr1.read().bits();
-        let temp_cfgr = self.adc.cfgr.read().bits();
-        let temp_cfgr2 = self.adc.cfgr2.read().bits();
-        let temp_ier = self.adc.ier.read().bits();
+    /// OVSR and OVSS fields of CFGR2.
+    const fn bits(self) -> u32 {
+        (self.ratio as u32) << 2 | (self.shift as u32) << 5
+    }
+}
 
-        self.adc.smpr2.write(|w| unsafe {w.bits(0)});
-        self.adc.sqr1.write(|w| unsafe {w.bits(0)});
-        self.adc.cfgr.write(|w| unsafe {w.bits(0)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(0)});
-        self.adc.ier.write(|w| unsafe {w.bits(0)});
+/// Injected sequence of 1 to 4 conversions and its trigger, as written to
+/// JSQR. With the injected queue enabled, each context written while the
+/// ADC runs is queued for the trigger after the current one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InjectedContext {
+    channels: [u8; 4],
+    len: usize,
+    trigger: Option<(InjectedTrigger, Edge)>,
+}
 
-        let vref_int : f32;
-        let vref_cal : u16;
+impl InjectedContext {
+    pub const fn new(channels: &[u8]) -> Self {
+        assert!(!channels.is_empty() && channels.len() <= 4, "Injected sequence must have 1 to 4 conversions");
+        let mut context = Self { channels: [0; 4], len: channels.len(), trigger: None };
+        let mut i = 0;
+        while i < channels.len() {
+            assert!(channels[i] <= MAX_CHANNEL, "No such ADC channel");
+            context.channels[i] = channels[i];
+            i += 1;
+        }
+        context
+    }
+
+    /// Start the sequence on `edge` of `trigger` instead of by software.
+    pub const fn trigger(mut self, trigger: InjectedTrigger, edge: Edge) -> Self {
+        self.trigger = Some((trigger, edge));
+        self
+    }
 
-        unsafe {
-            vref_cal = core::ptr::read_volatile(0x1FFF_75AA as *const u16);
+    const fn converts(&self, channel: u8) -> bool {
+        let mut i = 0;
+        while i < self.len {
+            if self.channels[i] == channel {
+                return true;
+            }
+            i += 1;
         }
+        false
+    }
 
-        if (vref_cal >= VREFINTCAL_MIN) && (vref_cal <= VREFINTCAL_MAX) {
-            vref_int = (ADC_FAC_CAL_VOL) * f32::from(vref_cal) / FLT_MAXCNT;
-        } else {
-            vref_int = VREFINT_CAL_DEF;
+    fn jsqr(&self) -> u32 {
+        let mut jsqr = self.len as u32 - 1;
+        if let Some((trigger, edge)) = self.trigger {
+            jsqr |= trigger.bits() << 2 | edge.bits() << 7;
         }
+        for (rank, channel) in self.channels[..self.len].iter().enumerate() {
+            jsqr |= (*channel as u32) << (9 + 6 * rank);
+        }
+        jsqr
+    }
+}
 
-        self.enable();
+/// Channel, sequence, trigger, DMA and interrupt configuration of one ADC.
+///
+/// Built with const methods so a board configuration in a `const` is checked
+/// at compile time. Channels default to 2.5 cycle sampling.
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    sample_times: [SampleTime; MAX_CHANNEL as usize + 1],
+    regular: [u8; 16],
+    regular_len: usize,
+    injected: Option<InjectedContext>,
+    injected_queue: bool,
+    injected_discontinuous: bool,
+    regular_trigger: Option<(RegularTrigger, Edge)>,
+    dma: DmaMode,
+    interrupts: u32,
+    regular_oversampling: Option<Oversampling>,
+    injected_oversampling: Option<Oversampling>,
+    watchdogs: [Option<u8>; 3],
+}
 
-        // Set sampling time for Vrefint
-        self.adc.smpr2.write(|w| w.smp18().variant(Cycles2475));
-        self.adc.sqr1.write(|w| w.sq1().variant(18));
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            sample_times: [SampleTime::Cycles2_5; MAX_CHANNEL as usize + 1],
+            regular: [0; 16],
+            regular_len: 0,
+            injected: None,
+            injected_queue: false,
+            injected_discontinuous: false,
+            regular_trigger: None,
+            dma: DmaMode::Disabled,
+            interrupts: 0,
+            regular_oversampling: None,
+            injected_oversampling: None,
+            watchdogs: [None; 3],
+        }
+    }
 
-        let mut vref_sum : f32 = 0.0;
-        for _i in 0..ADC_SMPLS {
-            self.adc.cr.modify(|_, w| w.adstart().set_bit());
-            while self.adc.isr.read().eoc().bit_is_clear() {}
-            self.adc.isr.modify(|_, w| w.eoc().clear_bit());
-            vref_sum = vref_sum + (self.adc.dr.read().bits() as f32);
+    pub const fn sample_time(mut self, channel: u8, time: SampleTime) -> Self {
+        assert!(channel <= MAX_CHANNEL, "No such ADC channel");
+        self.sample_times[channel as usize] = time;
+        self
+    }
+
+    /// Regular sequence, converted in order, 1 to 16 conversions.
+    pub const fn regular(mut self, channels: &[u8]) -> Self {
+        assert!(!channels.is_empty() && channels.len() <= 16, "Regular sequence must have 1 to 16 conversions");
+        let mut i = 0;
+        while i < channels.len() {
+            assert!(channels[i] <= MAX_CHANNEL, "No such ADC channel");
+            self.regular[i] = channels[i];
+            i += 1;
         }
+        self.regular_len = channels.len();
+        self
+    }
 
-        self.vref_cal = vref_int / ( ( vref_sum / f32::from(ADC_SMPLS) ) / FLT_MAXCNT );
+    /// Injected sequence, converted in order, 1 to 4 conversions.
+    pub const fn injected(mut self, channels: &[u8]) -> Self {
+        let context = InjectedContext::new(channels);
+        self.injected = match self.injected {
+            Some(InjectedContext { trigger: Some((trigger, edge)), .. }) => Some(context.trigger(trigger, edge)),
+            _ => Some(context),
+        };
+        self
+    }
 
-        self.adc.smpr2.write(|w| unsafe {w.bits(temp_smpr2)});
-        self.adc.sqr1.write(|w| unsafe {w.bits(temp_sqr1)});
-        self.adc.cfgr.write(|w| unsafe {w.bits(temp_cfgr)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(temp_cfgr2)});
-        self.adc.ier.write(|w| unsafe {w.bits(temp_ier)});
+    /// Start the regular sequence on `edge` of `trigger` instead of by software.
+    pub const fn regular_trigger(mut self, trigger: RegularTrigger, edge: Edge) -> Self {
+        self.regular_trigger = Some((trigger, edge));
+        self
     }
 
-    pub fn get_avg_reading(&mut self, chan: u16) -> u16 {
-        const ADC_SMPLS : u16 = 64;
+    /// Start the injected sequence on `edge` of `trigger` instead of by software.
+    pub const fn injected_trigger(mut self, trigger: InjectedTrigger, edge: Edge) -> Self {
+        let context = match self.injected {
+            Some(context) => context,
+            None => panic!("Injected trigger without an injected sequence"),
+        };
+        self.injected = Some(context.trigger(trigger, edge));
+        self
+    }
 
-        let temp_smpr2 = self.adc.smpr2.read().bits();
-        let temp_sqr1 = self.adc.sqr1.read().bits();
-        let temp_cfgr = self.adc.cfgr.read().bits();
-        let temp_cfgr2 = self.adc.cfgr2.read().bits();
-        let temp_ier = self.adc.ier.read().bits();
+    /// Enable the injected context queue. The sequence above is the first
+    /// context; later ones are queued with `Adc::queue_injected`. When the
+    /// queue runs dry the last context stays active.
+    pub const fn injected_queue(mut self) -> Self {
+        self.injected_queue = true;
+        self
+    }
 
-        self.adc.smpr2.write(|w| unsafe {w.bits(0)});
-        self.adc.sqr1.write(|w| unsafe {w.bits(0)});
-        self.adc.cfgr.write(|w| unsafe {w.bits(0)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(0)});
-        self.adc.ier.write(|w| unsafe {w.bits(0)});
+    /// Convert one rank of the injected sequence per trigger rather than the
+    /// whole sequence. JEOS is set once the last rank has converted, with
+    /// each rank's result in its own data register, so readings taken at
+    /// different points in a period stay in order.
+    pub const fn injected_discontinuous(mut self) -> Self {
+        self.injected_discontinuous = true;
+        self
+    }
 
-        self.enable();
+    pub const fn dma(mut self, mode: DmaMode) -> Self {
+        self.dma = mode;
+        self
+    }
 
-        // Set sampling time
-        self.adc.smpr2.write(|w| w.smp18().variant(Cycles2475));
-        self.adc.sqr1.write(|w| w.sq1().variant(chan as u8));
+    pub const fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupts |= 1 << interrupt as u32;
+        self
+    }
 
-        let mut res_sum : f32 = 0.0;
-        for _i in 0..ADC_SMPLS {
-            self.adc.cr.modify(|_, w| w.adstart().set_bit());
-            while self.adc.isr.read().eoc().bit_is_clear() {}
-            self.adc.isr.modify(|_, w| w.eoc().clear_bit());
-            res_sum = res_sum + (self.adc.dr.read().bits() as f32);
+    /// Oversample each regular conversion. An injected sequence arriving
+    /// part way through pauses the accumulation rather than restarting it.
+    pub const fn regular_oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.regular_oversampling = Some(oversampling);
+        self
+    }
+
+    /// Oversample each injected conversion.
+    pub const fn injected_oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.injected_oversampling = Some(oversampling);
+        self
+    }
+
+    /// Guard `channel` with `watchdog`, in whichever sequences convert it.
+    /// The window starts wide open; set it with `Adc::set_watchdog_window`.
+    pub const fn watchdog(mut self, watchdog: Watchdog, channel: u8) -> Self {
+        assert!(channel <= MAX_CHANNEL, "No such ADC channel");
+        self.watchdogs[watchdog as usize] = Some(channel);
+        self
+    }
+
+    /// Full-scale reading of the regular conversions.
+    pub const fn regular_full_scale(&self) -> f32 {
+        match self.regular_oversampling {
+            Some(oversampling) => oversampling.full_scale(),
+            None => oversampled_full_scale(0, 0),
         }
+    }
 
-        self.adc.smpr2.write(|w| unsafe {w.bits(temp_smpr2)});
-        self.adc.sqr1.write(|w| unsafe {w.bits(temp_sqr1)});
-        self.adc.cfgr.write(|w| unsafe {w.bits(temp_cfgr)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(temp_cfgr2)});
-        self.adc.ier.write(|w| unsafe {w.bits(temp_ier)});
-        (res_sum / f32::from(ADC_SMPLS)) as u16
+    /// Full-scale reading of the injected conversions.
+    pub const fn injected_full_scale(&self) -> f32 {
+        match self.injected_oversampling {
+            Some(oversampling) => oversampling.full_scale(),
+            None => oversampled_full_scale(0, 0),
+        }
     }
 
-    pub fn start(&self) {
-        self.enable();
-        self.adc.cr.modify(|_, w| w.adstart().set_bit().jadstart().set_bit());
+    /// Check the settings against each other.
+    pub const fn build(self) -> Self {
+        const REGULAR_IRQS: u32 = (1 << Interrupt::EndOfConversion as u32)
+            | (1 << Interrupt::EndOfSequence as u32) | (1 << Interrupt::Overrun as u32);
+        const INJECTED_IRQS: u32 = (1 << Interrupt::InjectedEndOfConversion as u32)
+            | (1 << Interrupt::InjectedEndOfSequence as u32);
+
+        assert!(self.regular_len > 0 || self.injected.is_some(), "ADC has nothing to convert");
+        if self.regular_len == 0 {
+            assert!(matches!(self.dma, DmaMode::Disabled), "DMA needs a regular sequence");
+            assert!(self.regular_trigger.is_none(), "Regular trigger without a regular sequence");
+            assert!(self.interrupts & REGULAR_IRQS == 0, "Regular interrupt without a regular sequence");
+            assert!(self.regular_oversampling.is_none(), "Regular oversampling without a regular sequence");
+        }
+        if self.injected.is_none() {
+            assert!(!self.injected_queue, "Injected queue without an injected sequence");
+            assert!(!self.injected_discontinuous, "Injected discontinuous mode without an injected sequence");
+            assert!(self.interrupts & INJECTED_IRQS == 0, "Injected interrupt without an injected sequence");
+            assert!(self.injected_oversampling.is_none(), "Injected oversampling without an injected sequence");
+        }
+        // Both groups share the ratio and shift fields of CFGR2.
+        if let (Some(regular), Some(injected)) = (self.regular_oversampling, self.injected_oversampling) {
+            assert!(regular.bits() == injected.bits(), "Regular and injected oversampling must match");
+        }
+        let mut i = 0;
+        while i < Watchdog::ALL.len() {
+            let watchdog = Watchdog::ALL[i];
+            match self.watchdogs[i] {
+                Some(channel) => {
+                    let regular = self.in_regular(channel);
+                    let injected = self.in_injected(channel);
+                    assert!(regular || injected, "Watchdog channel is not converted");
+                    // The window is scaled for one of the two.
+                    assert!(!(regular && injected)
+                        || self.regular_oversampling.is_some() == self.injected_oversampling.is_some(),
+                        "Watchdog channel is converted with and without oversampling");
+                }
+                None => assert!(self.interrupts & 1 << watchdog.bit() == 0, "Watchdog interrupt without a channel"),
+            }
+            i += 1;
+        }
+        self
     }
 
-    pub fn dr(&self) -> u32 { &self.adc.dr.read().bits() as *const _ as u32 }
+    const fn in_regular(&self, channel: u8) -> bool {
+        let mut i = 0;
+        while i < self.regular_len {
+            if self.regular[i] == channel {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
 
-    fn enable(&self) {
-        if self.adc.cr.read().aden().bit_is_clear() {
-            self.adc.isr.modify(|_, w| w.adrdy().clear_bit());
-            self.adc.cr.modify(|_, w| w.aden().set_bit());
+    /// Whether the first injected context converts `channel`.
+    const fn in_injected(&self, channel: u8) -> bool {
+        match &self.injected {
+            Some(context) => context.converts(channel),
+            None => false,
+        }
+    }
 
-            while self.adc.isr.read().adrdy().bit_is_clear() {}
+    /// Channel counts per threshold step of `watchdog`. Oversampled results
+    /// are compared by their 12 most significant bits.
+    fn watchdog_step(&self, watchdog: Watchdog, channel: u8) -> f32 {
+        let oversampled = (self.in_regular(channel) && self.regular_oversampling.is_some())
+            || (self.in_injected(channel) && self.injected_oversampling.is_some());
+        let step = if oversampled { 16.0 } else { 1.0 };
+        match watchdog {
+            Watchdog::Awd1 => step,
+            Watchdog::Awd2 | Watchdog::Awd3 => step * 16.0,
         }
     }
 
-    // Clear JEOS interrupt flag
-    pub fn clear_jeos(&self) { self.adc.isr.modify(|_, w| w.jeos().clear_bit()); }
+    /// SMPR1 and SMPR2 values.
+    fn smpr(&self) -> [u32; 2] {
+        let mut smpr = [0; 2];
+        for (channel, time) in self.sample_times.iter().enumerate() {
+            smpr[channel / 10] |= (*time as u32) << (3 * (channel % 10));
+        }
+        smpr
+    }
 
-    pub fn read_jeos(&self) -> bool { self.adc.isr.read().jeos().bit() }
+    /// SQR1 to SQR4 values. SQR1 holds the length and four conversions, the
+    /// rest five each.
+    fn sqr(&self) -> [u32; 4] {
+        let mut sqr = [0, 0, 0, 0];
+        if self.regular_len > 0 {
+            sqr[0] = self.regular_len as u32 - 1;
+        }
+        for (rank, channel) in self.regular[..self.regular_len].iter().enumerate() {
+            let slot = rank + 1;
+            sqr[slot / 5] |= (*channel as u32) << (6 * (slot % 5));
+        }
+        sqr
+    }
 
-    pub fn get_inj_data(&self) -> u16{ self.adc.jdr1.read().jdata().bits() as u16 }
+    /// CFGR2 value. Regular oversampling runs in continued mode, a whole
+    /// burst per trigger.
+    fn cfgr2(&self) -> u32 {
+        let mut cfgr2 = 0;
+        if let Some(oversampling) = self.regular_oversampling {
+            cfgr2 |= 1 | oversampling.bits();
+        }
+        if let Some(oversampling) = self.injected_oversampling {
+            cfgr2 |= 1 << 1 | oversampling.bits();
+        }
+        cfgr2
+    }
+
+    fn jsqr(&self) -> u32 {
+        match &self.injected {
+            Some(context) => context.jsqr(),
+            None => 0,
+        }
+    }
 }
 
-impl Adc2 {
-    pub fn new(adc: ADC2) -> Self {
-        Self { adc, vref_cal: 0.0 }
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn setup(&self) {
+/// ADC instances sharing the ADC1 register layout.
+pub trait Instance: Deref<Target = adc1::RegisterBlock> {
+    /// ADC number.
+    const ID: u8;
+    /// Whether VREFINT is wired to this ADC, for measuring VDDA.
+    const HAS_VREFINT: bool;
+    /// DMAMUX request ID of the regular conversions.
+    const DMAREQ: u8;
+}
+
+impl Instance for ADC1 {
+    const ID: u8 = 1;
+    const HAS_VREFINT: bool = true;
+    const DMAREQ: u8 = 5;
+}
+
+impl Instance for ADC2 {
+    const ID: u8 = 2;
+    const HAS_VREFINT: bool = false;
+    const DMAREQ: u8 = 36;
+}
+
+pub struct Adc<ADC> {
+    adc: ADC,
+    vref_cal: f32,
+    /// Guarded channel and counts per threshold step of each watchdog.
+    watchdogs: [Option<(u8, f32)>; 3],
+    /// The configuration has a regular and an injected sequence to start.
+    sequences: (bool, bool),
+}
+
+pub type Adc1 = Adc<ADC1>;
+pub type Adc2 = Adc<ADC2>;
+
+/// Pairing of ADC1 and ADC2, with ADC1 as master.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DualMode {
+    /// Regular and injected conversions simultaneous.
+    RegularInjected,
+    /// Regular conversions simultaneous, injected conversions independent,
+    /// e.g. with ADC1 running an injected queue.
+    Regular,
+}
+
+/// Put ADC1 and ADC2 in `mode` and enable VREFINT and the temperature
+/// sensor. Both ADCs must be disabled.
+pub fn setup_common(adc12: ADC12_COMMON, mode: DualMode) {
+    let dual = match mode {
+        DualMode::RegularInjected => DUAL_A::DualRj,
+        DualMode::Regular => DUAL_A::DualR,
+    };
+    adc12.ccr.modify(|_, w| w.dual().variant(dual));
+    adc12.ccr.modify(|_, w| w.vrefen().set_bit().vsensesel().set_bit().dmacfg().set_bit());
+}
+
+/// Factory calibration of the internal temperature sensor, TS_CAL1 and
+/// TS_CAL2 from system memory.
+pub fn internal_sensor_cal() -> InternalSensor {
+    // NOTE(unsafe): Read-only factory data at fixed addresses.
+    unsafe {
+        InternalSensor {
+            cal1: core::ptr::read_volatile(0x1FFF_75A8 as *const u16),
+            cal2: core::ptr::read_volatile(0x1FFF_75CA as *const u16),
+        }
+    }
+}
+
+impl<ADC: Instance> Adc<ADC> {
+    pub fn new(adc: ADC) -> Self {
+        Self { adc, vref_cal: 0.0, watchdogs: [None; 3], sequences: (false, false) }
+    }
+
+    /// Power up and calibrate the ADC, measure VDDA where VREFINT is
+    /// available and apply `config`. Leaves the ADC enabled but not started.
+    pub fn setup(&mut self, config: &Config) {
         self.adc.cr.modify(|_, w| w.deeppwd().clear_bit());
 
         self.adc.cr.modify(|_, w| w.advregen().set_bit());
@@ -196,72 +602,148 @@ impl Adc2 {
         self.adc.cr.modify(|_, w| w.adcal().set_bit().adcaldif().single_ended());
         while self.adc.cr.read().adcal().bit_is_set() {} // Wait for the calibration
 
-        self.adc.cfgr.modify(|_, w| w.jqdis().set_bit().dmaen().set_bit().dmacfg().set_bit());
-        self.adc.cfgr2.write(|w| unsafe {w.bits(0)});
+        if ADC::HAS_VREFINT {
+            // VREFINT is measured at the native 12 bits.
+            self.adc.cfgr2.reset();
+            self.calc_vref();
+        }
 
-        // Set sampling time for I_B and VM
-        self.adc.smpr1.write(|w| w.smp4().variant(Cycles245));
-        self.adc.smpr2.write(|w| w.smp16().variant(Cycles25));
+        self.configure(config);
+    }
 
-        //set  regular sequence to just VM
-        self.adc.sqr1.write(|w| w.l().variant(7).sq1().variant(1).sq2().variant(1)
-            .sq3().variant(1).sq4().variant(1));
-        self.adc.sqr2.write(|w| w.sq5().variant(1).sq6().variant(1)
-            .sq7().variant(1).sq8().variant(1));
+    /// Apply `config`. The ADC must not be converting.
+    pub fn configure(&mut self, config: &Config) {
+        let [smpr1, smpr2] = config.smpr();
+        self.adc.smpr1.write(|w| unsafe {w.bits(smpr1)});
+        self.adc.smpr2.write(|w| unsafe {w.bits(smpr2)});
+
+        let [sqr1, sqr2, sqr3, sqr4] = config.sqr();
+        self.adc.sqr1.write(|w| unsafe {w.bits(sqr1)});
+        self.adc.sqr2.write(|w| unsafe {w.bits(sqr2)});
+        self.adc.sqr3.write(|w| unsafe {w.bits(sqr3)});
+        self.adc.sqr4.write(|w| unsafe {w.bits(sqr4)});
+
+        self.adc.jsqr.write(|w| unsafe {w.bits(config.jsqr())});
+        self.sequences = (config.regular_len > 0, config.injected.is_some());
+
+        let (extsel, exten) = match config.regular_trigger {
+            Some((trigger, edge)) => (trigger.bits(), edge.bits()),
+            None => (0, 0),
+        };
+        self.adc.cfgr.write(|w| unsafe {
+            w.jqdis().bit(!config.injected_queue).jdiscen().bit(config.injected_discontinuous)
+                .ovrmod().set_bit()
+                .dmaen().bit(config.dma != DmaMode::Disabled)
+                .dmacfg().bit(config.dma == DmaMode::Circular)
+                .extsel().bits(extsel as u8).exten().bits(exten as u8)
+        });
+        self.adc.cfgr2.write(|w| unsafe {w.bits(config.cfgr2())});
+
+        // Watchdog windows start wide open.
+        self.adc.tr1.write(|w| unsafe {w.bits(Watchdog::Awd1.max_threshold() << 16)});
+        self.adc.tr2.write(|w| unsafe {w.bits(Watchdog::Awd2.max_threshold() << 16)});
+        self.adc.tr3.write(|w| unsafe {w.bits(Watchdog::Awd3.max_threshold() << 16)});
+        self.adc.awd2cr.write(|w| unsafe {w.bits(0)});
+        self.adc.awd3cr.write(|w| unsafe {w.bits(0)});
+        for watchdog in Watchdog::ALL {
+            self.watchdogs[watchdog as usize] = config.watchdogs[watchdog as usize]
+                .map(|channel| (channel, config.watchdog_step(watchdog, channel)));
+            let Some(channel) = config.watchdogs[watchdog as usize] else { continue };
+            match watchdog {
+                Watchdog::Awd1 => self.adc.cfgr.modify(|_, w| unsafe {
+                    w.awd1sgl().set_bit().awd1ch().bits(channel)
+                        .awd1en().bit(config.in_regular(channel))
+                        .jawd1en().bit(config.in_injected(channel))
+                }),
+                Watchdog::Awd2 => self.adc.awd2cr.write(|w| unsafe {w.bits(1 << channel)}),
+                Watchdog::Awd3 => self.adc.awd3cr.write(|w| unsafe {w.bits(1 << channel)}),
+            }
+        }
 
-        // Set current injected channels
-        self.adc.jsqr.write(|w| w.jl().variant(1).jextsel().variant(JEXTSEL_A::Tim1Trgo)
-            .jexten().variant(JEXTEN_A::RisingEdge).jsq1().variant(16).jsq2().variant(16));
-        self.adc.cfgr.modify(|_, w| w.ovrmod().variant(OVRMOD_A::Overwrite)
-            .extsel().variant(EXTSEL_A::Tim1Trgo2).exten().variant(EXTEN_A::RisingEdge));
+        self.adc.ier.write(|w| unsafe {w.bits(config.interrupts)});
     }
 
-    pub fn get_avg_reading(&mut self, chan: u16) -> u16 {
-        const ADC_SMPLS : u16 = 64;
+    fn calc_vref(&mut self) {
+        const VREFINTCAL_MIN : u16 = 1570;
+        const VREFINTCAL_MAX : u16 = 1734;
+        const ADC_FAC_CAL_VOL : f32 = 3.0;
+        const FLT_MAXCNT : f32 = 4095.0;
+        const VREFINT_CAL_DEF : f32 = 1.212;
+        const ADC_SMPLS : u16 = 128;
+
+        let vref_cal : u16 = unsafe { core::ptr::read_volatile(0x1FFF_75AA as *const u16) };
+
+        let vref_int = if (VREFINTCAL_MIN..=VREFINTCAL_MAX).contains(&vref_cal) {
+            (ADC_FAC_CAL_VOL) * f32::from(vref_cal) / FLT_MAXCNT
+        } else {
+            VREFINT_CAL_DEF
+        };
 
+        let reading = self.sample_single(VREFINT_CHANNEL, ADC_SMPLS);
+        self.vref_cal = vref_int / (reading / FLT_MAXCNT);
+    }
+
+    /// Average of `samples` software triggered conversions of `chan` with
+    /// 247.5 cycle sampling. Regular oversampling is left as configured, so
+    /// the result has the full scale of the regular conversions.
+    /// Configuration is restored afterwards.
+    fn sample_single(&mut self, chan: u8, samples: u16) -> f32 {
+        let temp_smpr1 = self.adc.smpr1.read().bits();
         let temp_smpr2 = self.adc.smpr2.read().bits();
         let temp_sqr1 = self.adc.sqr1.read().bits();
         let temp_cfgr = self.adc.cfgr.read().bits();
-        let temp_cfgr2 = self.adc.cfgr2.read().bits();
         let temp_ier = self.adc.ier.read().bits();
 
-        self.adc.smpr2.write(|w| unsafe {w.bits(0)});
-        self.adc.sqr1.write(|w| unsafe {w.bits(0)});
+        let config = Config::new()
+            .sample_time(chan, SampleTime::Cycles247_5)
+            .regular(&[chan])
+            .build();
+        let [smpr1, smpr2] = config.smpr();
+        self.adc.smpr1.write(|w| unsafe {w.bits(smpr1)});
+        self.adc.smpr2.write(|w| unsafe {w.bits(smpr2)});
+        self.adc.sqr1.write(|w| unsafe {w.bits(config.sqr()[0])});
         self.adc.cfgr.write(|w| unsafe {w.bits(0)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(0)});
         self.adc.ier.write(|w| unsafe {w.bits(0)});
 
         self.enable();
 
-        // Set sampling time
-        self.adc.smpr2.write(|w| w.smp18().variant(Cycles2475));
-        self.adc.sqr1.write(|w| w.sq1().variant(chan as u8));
-
         let mut res_sum : f32 = 0.0;
-        for _i in 0..ADC_SMPLS {
+        for _i in 0..samples {
             self.adc.cr.modify(|_, w| w.adstart().set_bit());
             while self.adc.isr.read().eoc().bit_is_clear() {}
             self.adc.isr.modify(|_, w| w.eoc().clear_bit());
-            res_sum = res_sum + (self.adc.dr.read().bits() as f32);
+            res_sum += self.adc.dr.read().bits() as f32;
         }
 
+        self.adc.smpr1.write(|w| unsafe {w.bits(temp_smpr1)});
         self.adc.smpr2.write(|w| unsafe {w.bits(temp_smpr2)});
         self.adc.sqr1.write(|w| unsafe {w.bits(temp_sqr1)});
         self.adc.cfgr.write(|w| unsafe {w.bits(temp_cfgr)});
-        self.adc.cfgr2.write(|w| unsafe {w.bits(temp_cfgr2)});
         self.adc.ier.write(|w| unsafe {w.bits(temp_ier)});
-        (res_sum / f32::from(ADC_SMPLS)) as u16
+        res_sum / f32::from(samples)
     }
 
+    pub fn get_avg_reading(&mut self, chan: u8) -> u16 {
+        const ADC_SMPLS : u16 = 64;
+        self.sample_single(chan, ADC_SMPLS) as u16
+    }
+
+    /// Start the sequences the configuration has. An empty sequence is left
+    /// stopped, as it would convert channel 0 on every trigger.
     pub fn start(&self) {
         self.enable();
-        self.adc.cr.modify(|_, w| w.adstart().set_bit().jadstart().set_bit());
+        let (regular, injected) = self.sequences;
+        self.adc.cr.modify(|_, w| w.adstart().bit(regular).jadstart().bit(injected));
     }
 
-    pub fn dr(&self) -> u32 { &self.adc.dr.read().bits() as *const _ as u32 }
+    /// Address of the regular data register, for DMA.
+    pub fn dr(&self) -> u32 { &self.adc.dr as *const _ as u32 }
+
+    /// Analog supply voltage measured against VREFINT during setup. Zero on
+    /// an ADC without VREFINT.
+    pub fn vdda(&self) -> f32 { self.vref_cal }
 
     fn enable(&self) {
-        //if read_reg!(adc, self.adc, CR, ADEN == 0) {
         if self.adc.cr.read().aden().bit_is_clear() {
             self.adc.isr.modify(|_, w| w.adrdy().clear_bit());
             self.adc.cr.modify(|_, w| w.aden().set_bit());
@@ -270,10 +752,260 @@ impl Adc2 {
         }
     }
 
-    // Clear JEOS interrupt flag
-    pub fn clear_jeos(&self) { self.adc.isr.modify(|_, w| w.jeos().clear_bit()); }
+    // Clear JEOS interrupt flag. ISR flags clear by writing one, leaving
+    // the others alone.
+    pub fn clear_jeos(&self) { self.adc.isr.write(|w| unsafe {w.bits(1 << 6)}); }
 
     pub fn read_jeos(&self) -> bool { self.adc.isr.read().jeos().bit() }
 
-    pub fn get_inj_data(&self) -> u16{ self.adc.jdr1.read().jdata().bits() as u16 }
+    /// Clear the JEOC flag, set at the end of each injected conversion.
+    pub fn clear_jeoc(&self) { self.adc.isr.write(|w| unsafe {w.bits(1 << 5)}); }
+
+    pub fn read_jeoc(&self) -> bool { self.adc.isr.read().jeoc().bit() }
+
+    pub fn get_inj_data(&self) -> u16{ self.adc.jdr1.read().jdata().bits() }
+
+    /// Results of the first `N` injected ranks, in rank order.
+    pub fn get_inj_ranks<const N: usize>(&self) -> [u16; N] {
+        assert!(N <= 4, "Injected sequences have at most 4 ranks");
+        core::array::from_fn(|rank| match rank {
+            0 => self.adc.jdr1.read().jdata().bits(),
+            1 => self.adc.jdr2.read().jdata().bits(),
+            2 => self.adc.jdr3.read().jdata().bits(),
+            _ => self.adc.jdr4.read().jdata().bits(),
+        })
+    }
+
+    /// Latest regular conversion result.
+    pub fn get_reg_data(&self) -> u16 { self.adc.dr.read().bits() as u16 }
+
+    /// Queue an injected context for the trigger after the one pending. The
+    /// queue holds two contexts; see `take_queue_overflow`.
+    pub fn queue_injected(&self, context: &InjectedContext) {
+        self.adc.jsqr.write(|w| unsafe {w.bits(context.jsqr())});
+    }
+
+    /// Whether a context was written to a full queue and lost since the
+    /// last call. Clears the flag.
+    pub fn take_queue_overflow(&self) -> bool {
+        const JQOVF: u32 = 1 << 10;
+        let overflow = self.adc.isr.read().bits() & JQOVF != 0;
+        if overflow {
+            self.adc.isr.write(|w| unsafe {w.bits(JQOVF)});
+        }
+        overflow
+    }
+
+    /// Trip `watchdog` on conversions below `low` or above `high`, in counts
+    /// of the guarded channel. May be changed while converting.
+    pub fn set_watchdog_window(&self, watchdog: Watchdog, low: f32, high: f32) {
+        let (_, step) = self.watchdogs[watchdog as usize].expect("Watchdog has no channel");
+        let max = watchdog.max_threshold();
+        // Round outwards so the window is never narrower than asked.
+        let lt = libm::floorf(low / step).clamp(0.0, max as f32) as u32;
+        let ht = libm::ceilf(high / step).clamp(0.0, max as f32) as u32;
+        let tr = lt | ht << 16;
+        match watchdog {
+            Watchdog::Awd1 => self.adc.tr1.write(|w| unsafe {w.bits(tr)}),
+            Watchdog::Awd2 => self.adc.tr2.write(|w| unsafe {w.bits(tr)}),
+            Watchdog::Awd3 => self.adc.tr3.write(|w| unsafe {w.bits(tr)}),
+        }
+    }
+
+    /// First armed watchdog that has tripped. Its flag is cleared and its
+    /// interrupt disabled, so a channel stuck out of its window does not
+    /// keep interrupting. It stays disarmed until `clear_watchdog`.
+    pub fn take_watchdog_event(&self) -> Option<WatchdogEvent> {
+        let pending = self.adc.isr.read().bits() & self.adc.ier.read().bits();
+        for watchdog in Watchdog::ALL {
+            let mask = 1 << watchdog.bit();
+            if pending & mask == 0 {
+                continue;
+            }
+            self.adc.ier.modify(|r, w| unsafe {w.bits(r.bits() & !mask)});
+            self.adc.isr.write(|w| unsafe {w.bits(mask)});
+            if let Some((channel, _)) = self.watchdogs[watchdog as usize] {
+                return Some(WatchdogEvent { adc: ADC::ID, watchdog, channel });
+            }
+        }
+        None
+    }
+
+    /// Re-arm `watchdog` after a trip: clear any flag it has raised since
+    /// and enable its interrupt again.
+    pub fn clear_watchdog(&self, watchdog: Watchdog) {
+        let mask = 1 << watchdog.bit();
+        self.adc.isr.write(|w| unsafe {w.bits(mask)});
+        self.adc.ier.modify(|r, w| unsafe {w.bits(r.bits() | mask)});
+    }
+}
+
+/// Phase current readings of one PWM period.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PhaseCurrents {
+    /// Injected readings: ADC1 then ADC2, converted simultaneously, or the
+    /// first then second sample of a single shunt.
+    pub raw: [u16; 2],
+    /// DWT cycle count of the period, on a grid of whole PWM periods from
+    /// the first sample's read time. Spaced like the triggers, free of
+    /// interrupt latency jitter.
+    pub cycles: u32,
+    /// Number of the PWM period that produced the readings.
+    pub period: u32,
+    /// Periods since the previous sample whose readings were lost.
+    pub missed: u32,
+}
+
+impl PhaseCurrents {
+    /// Shunt currents in amps, given the readings at zero current.
+    pub fn amps(&self, sense: &Sense, offsets: [f32; 2]) -> [f32; 2] {
+        [
+            sense.amps(self.raw[0] as f32, offsets[0]),
+            sense.amps(self.raw[1] as f32, offsets[1]),
+        ]
+    }
+}
+
+/// ADC1 as master and ADC2 as slave. In `DualMode::RegularInjected` the
+/// injected pair of each PWM period is read as one sample; with a single
+/// shunt, ADC1's two injected ranks are.
+///
+/// Samples are timestamped from the DWT cycle counter, which must be
+/// running, so a JEOS that went unserviced shows up as a missed period.
+pub struct DualAdc {
+    adc1: Adc1,
+    adc2: Adc2,
+    periods: PeriodCounter,
+}
+
+impl DualAdc {
+    /// Take both ADCs, set up and configured, with a PWM period of
+    /// `period_cycles` core clock cycles.
+    pub fn new(adc1: Adc1, adc2: Adc2, period_cycles: u32) -> Self {
+        Self { adc1, adc2, periods: PeriodCounter::new(period_cycles) }
+    }
+
+    pub fn adc1(&self) -> &Adc1 {
+        &self.adc1
+    }
+
+    pub fn adc2(&self) -> &Adc2 {
+        &self.adc2
+    }
+
+    /// Start converting. The slave is enabled first so it is ready when the
+    /// master starts both.
+    pub fn start(&self) {
+        self.adc2.start();
+        self.adc1.start();
+    }
+
+    /// Readings of the latest injected sequence, if one has completed since
+    /// the last call. Both ADCs finish together with equal sampling times,
+    /// so the master's JEOS covers the pair.
+    pub fn take_currents(&mut self) -> Option<PhaseCurrents> {
+        if !self.adc1.read_jeos() {
+            return None;
+        }
+        let raw = loop {
+            self.adc1.clear_jeos();
+            let raw = [self.adc1.get_inj_data(), self.adc2.get_inj_data()];
+            // A sequence completing mid-read may have torn the pair; take
+            // the newer one instead.
+            if !self.adc1.read_jeos() {
+                break raw;
+            }
+        };
+        Some(self.stamp(raw))
+    }
+
+    /// Both single-shunt readings of the latest period, if its injected
+    /// sequence has completed since the last call. ADC1 converts one rank
+    /// per trigger, the first sample into JDR1 and the second into JDR2.
+    /// A pair torn by the next period's first conversion landing mid-read
+    /// is dropped, and shows up as a missed period.
+    pub fn take_shunt_currents(&mut self) -> Option<PhaseCurrents> {
+        if !self.adc1.read_jeos() {
+            return None;
+        }
+        self.adc1.clear_jeoc();
+        self.adc1.clear_jeos();
+        let raw = self.adc1.get_inj_ranks();
+        if self.adc1.read_jeoc() {
+            return None;
+        }
+        Some(self.stamp(raw))
+    }
+
+    fn stamp(&mut self, raw: [u16; 2]) -> PhaseCurrents {
+        let (period, missed) = self.periods.tick(DWT::cycle_count());
+        PhaseCurrents { raw, cycles: self.periods.cycles(), period, missed }
+    }
+}
+
+/// Regular conversion results streamed by a circular DMA channel into a
+/// buffer of two halves. The DMA fills one half while the other is read.
+pub struct RegularDma {
+    channel: DMAChannel,
+    buf: &'static mut [u16],
+}
+
+impl RegularDma {
+    /// Route the regular conversions of `adc` to `channel`, writing into
+    /// `buf`. Each half of `buf` must hold whole regular sequences of
+    /// `config` so the halves line up with the sequence.
+    pub fn new<ADC: Instance>(adc: &Adc<ADC>, config: &Config, dmamux: &DMAMux,
+                              channel: DMAChannel, buf: &'static mut [u16]) -> Self {
+        assert!(config.dma == DmaMode::Circular, "ADC is not set up for circular DMA");
+        assert!(buf.len().is_multiple_of(2 * config.regular_len), "DMA buffer halves must hold whole sequences");
+        dmamux.set(channel.dmamux_channel(), ADC::DMAREQ);
+        channel.setup_adc_circ(adc.dr());
+        Self { channel, buf }
+    }
+
+    /// Start the transfer. Must happen before the ADC is started, or the
+    /// halves will not line up with the sequence.
+    pub fn start(&mut self) {
+        self.channel.start_adc_rx(self.buf);
+    }
+
+    /// Mean of the half the DMA has just filled, clearing its interrupt
+    /// flag. None if neither half has completed. Every result counts the
+    /// same, so this suits a sequence repeating one channel.
+    pub fn take_average(&mut self) -> Option<f32> {
+        let samples = self.take_half()?;
+        let sum: u32 = samples.iter().map(|s| *s as u32).sum();
+        Some(sum as f32 / samples.len() as f32)
+    }
+
+    /// Mean of each of the `R` ranks of the sequence over the half the DMA
+    /// has just filled, as `take_average`, for a sequence mixing channels.
+    pub fn take_rank_averages<const R: usize>(&mut self) -> Option<[f32; R]> {
+        let samples = self.take_half()?;
+        assert!(samples.len() % R == 0, "DMA buffer halves must hold whole sequences");
+        let mut sums = [0u32; R];
+        for (i, s) in samples.iter().enumerate() {
+            sums[i % R] += *s as u32;
+        }
+        let sequences = (samples.len() / R) as f32;
+        Some(sums.map(|sum| sum as f32 / sequences))
+    }
+
+    /// The half the DMA has just filled, clearing its interrupt flag.
+    fn take_half(&mut self) -> Option<&[u16]> {
+        let half = self.buf.len() / 2;
+        let samples = if self.channel.tcif() {
+            // A half transfer still pending is stale by now.
+            self.channel.clear_flags();
+            &self.buf[half..]
+        } else if self.channel.htif() {
+            self.channel.clear_htif();
+            &self.buf[..half]
+        } else {
+            return None;
+        };
+        // The DMA wrote the buffer behind the compiler's back.
+        compiler_fence(Ordering::SeqCst);
+        Some(samples)
+    }
 }
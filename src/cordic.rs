@@ -1,60 +1,506 @@
-use stm32ral::{cordic, read_reg, write_reg, modify_reg};
+#![allow(dead_code)]
+
+use core::marker::PhantomData;
+
+use stm32g4xx_hal::stm32::CORDIC;
+use stm32g4xx_hal::stm32::cordic::csr::{ARGSIZE_A, FUNC_A, NARGS_A, NRES_A, RESSIZE_A};
+use ufoc::foc::fixed::{q15_to_q31, Q15, Q31, SinCosQ31};
+use ufoc::foc::{trig, ElecAngle};
+
+use crate::dma::{DMAChannel, DMAMux};
+
+/// DMAMUX request IDs of the Cordic.
+const DMAREQ_CORDIC_READ: u8 = 100;
+const DMAREQ_CORDIC_WRITE: u8 = 101;
 
 fn float_to_q31(input: f32) -> i32 {
     let out = input * 2147483648.0;
-    let out_round;
-    if out > 0.0 {
-        out_round = out + 0.5;
-    } else {
-        out_round = out - 0.5;
-    }
+    let out_round = if out > 0.0 { out + 0.5 } else { out - 0.5 };
     out_round as i32
 }
 
 fn q31_to_float(input: i32) -> f32 {
-    let out = input as f32 / 2147483648.0;
-    out
+    input as f32 / 2147483648.0
+}
+
+/// Functions implemented by the CORDIC co-processor.
+///
+/// Angles are in units of pi, so the full Q1.31 range covers one turn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// Arguments: angle, modulus. Results: m*cos, m*sin.
+    Cosine,
+    /// Arguments: angle, modulus. Results: m*sin, m*cos.
+    Sine,
+    /// Arguments: x, y. Results: atan2(y, x), modulus.
+    Phase,
+    /// Arguments: x, y. Results: modulus, atan2(y, x).
+    Modulus,
+    /// Argument: x * 2^-n. Result: atan(x) * 2^-n.
+    Arctangent,
+    /// Argument: x * 2^-1. Results: cosh(x) * 2^-1, sinh(x) * 2^-1.
+    HyperbolicCosine,
+    /// Argument: x * 2^-1. Results: sinh(x) * 2^-1, cosh(x) * 2^-1.
+    HyperbolicSine,
+    /// Argument: x * 2^-1. Result: atanh(x) * 2^-1.
+    Arctanh,
+    /// Argument: x * 2^-n. Result: ln(x) * 2^-(n + 1).
+    NaturalLog,
+    /// Argument: x * 2^-n. Result: sqrt(x) * 2^-n.
+    SquareRoot,
+}
+
+impl Function {
+    fn variant(self) -> FUNC_A {
+        match self {
+            Function::Cosine => FUNC_A::Cosine,
+            Function::Sine => FUNC_A::Sine,
+            Function::Phase => FUNC_A::Phase,
+            Function::Modulus => FUNC_A::Modulus,
+            Function::Arctangent => FUNC_A::Arctangent,
+            Function::HyperbolicCosine => FUNC_A::HyperbolicCosine,
+            Function::HyperbolicSine => FUNC_A::HyperbolicSine,
+            Function::Arctanh => FUNC_A::Arctanh,
+            Function::NaturalLog => FUNC_A::NaturalLogarithm,
+            Function::SquareRoot => FUNC_A::SquareRoot,
+        }
+    }
+
+    /// Number of arguments written per calculation.
+    pub fn nargs(self) -> usize {
+        match self {
+            Function::Cosine | Function::Sine | Function::Phase | Function::Modulus => 2,
+            _ => 1,
+        }
+    }
+
+    /// Number of results produced per calculation.
+    pub fn nres(self) -> usize {
+        match self {
+            Function::Cosine | Function::Sine | Function::Phase | Function::Modulus
+            | Function::HyperbolicCosine | Function::HyperbolicSine => 2,
+            _ => 1,
+        }
+    }
+
+    /// Iterations / 4 giving close to full Q1.31 accuracy for this function.
+    pub fn default_precision(self) -> u8 {
+        match self {
+            Function::Cosine | Function::Sine => 5,
+            Function::SquareRoot => 3,
+            _ => 6,
+        }
+    }
+
+    /// Lowest and highest scale exponent the function supports.
+    pub fn scales(self) -> (u8, u8) {
+        match self {
+            Function::Cosine | Function::Sine | Function::Phase | Function::Modulus => (0, 0),
+            Function::Arctangent => (0, 7),
+            Function::HyperbolicCosine | Function::HyperbolicSine | Function::Arctanh => (1, 1),
+            Function::NaturalLog => (1, 4),
+            Function::SquareRoot => (0, 2),
+        }
+    }
+
+    /// Scale factor exponent valid for any argument, where one exists.
+    pub fn default_scale(self) -> u8 {
+        match self {
+            Function::HyperbolicCosine | Function::HyperbolicSine | Function::Arctanh
+            | Function::NaturalLog => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Complete CORDIC configuration for one function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub function: Function,
+    /// Number of iterations / 4, 1..=15.
+    pub precision: u8,
+    /// Argument and result scale exponent `n`, 0..=7.
+    pub scale: u8,
+    /// Use 16-bit Q1.15 arguments and results, packed two per register
+    /// access: first argument or result in the low half-word.
+    pub packed: bool,
+}
+
+impl Config {
+    /// Configuration with the default precision and scale for `function`.
+    pub fn new(function: Function) -> Self {
+        Self {
+            function,
+            precision: function.default_precision(),
+            scale: function.default_scale(),
+            packed: false,
+        }
+    }
+
+    /// Switch to packed Q1.15 arguments and results.
+    pub fn packed(self) -> Self {
+        Self { packed: true, ..self }
+    }
+
+    /// Panics unless `precision` is 1..=15.
+    pub fn precision(self, precision: u8) -> Self {
+        assert!((1..=15).contains(&precision), "Cordic precision is 1 to 15");
+        Self { precision, ..self }
+    }
+
+    /// Panics if the function does not support `scale`, see
+    /// `Function::scales`.
+    pub fn scale(self, scale: u8) -> Self {
+        let (min, max) = self.function.scales();
+        assert!((min..=max).contains(&scale), "Scale not supported by this Cordic function");
+        Self { scale, ..self }
+    }
+
+    /// WDATA writes per calculation, as set up by `Cordic::configure`.
+    pub fn arg_words(self) -> usize {
+        if self.packed {
+            return 1;
+        }
+        match self.function {
+            Function::Cosine | Function::Sine => 1,
+            f => f.nargs(),
+        }
+    }
+
+    /// RDATA reads per calculation.
+    pub fn res_words(self) -> usize {
+        if self.packed {
+            1
+        } else {
+            self.function.nres()
+        }
+    }
+}
+
+/// Sine and cosine of an angle, scaled by the modulus argument.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SinCosF32 {
+    pub sin: f32,
+    pub cos: f32,
+}
+
+/// Polar form of a vector. The angle is in units of pi.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Polar {
+    pub angle: f32,
+    pub modulus: f32,
+}
+
+/// Sine and cosine of an angle in Q1.15.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SinCosQ15 {
+    pub sin: Q15,
+    pub cos: Q15,
+}
+
+impl From<SinCosQ15> for SinCosQ31 {
+    fn from(sc: SinCosQ15) -> Self {
+        SinCosQ31 { sin: q15_to_q31(sc.sin), cos: q15_to_q31(sc.cos) }
+    }
+}
+
+/// Hyperbolic sine and cosine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hyperbolic {
+    pub sinh: f32,
+    pub cosh: f32,
 }
 
 pub struct Cordic {
-    cordic: cordic::Instance,
+    cordic: CORDIC,
+    config: Option<Config>,
+}
+
+/// Pair of DMA1 channels routed to the Cordic argument and result registers.
+pub struct CordicDma {
+    write: DMAChannel,
+    read: DMAChannel,
+}
+
+impl CordicDma {
+    /// Route the Cordic write requests to `write` and read requests to `read`.
+    pub fn new(dmamux: &DMAMux, write: DMAChannel, read: DMAChannel) -> Self {
+        dmamux.set(write.dmamux_channel(), DMAREQ_CORDIC_WRITE);
+        dmamux.set(read.dmamux_channel(), DMAREQ_CORDIC_READ);
+        Self { write, read }
+    }
+}
+
+/// Batch calculation running through DMA. The Cordic and both buffers stay
+/// borrowed until it is dropped, which stops the transfer.
+pub struct Batch<'a> {
+    cordic: &'a mut Cordic,
+    dma: &'a CordicDma,
+    _buffers: PhantomData<(&'a [u32], &'a mut [u32])>,
+}
+
+impl Batch<'_> {
+    /// All results have been written to memory.
+    pub fn is_done(&self) -> bool {
+        self.dma.read.tcif()
+    }
+
+    /// Block until all results have been written to memory.
+    pub fn wait(self) {
+        while !self.is_done() {}
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        self.cordic.cordic.csr.modify(|_, w| w.dmawen().clear_bit().dmaren().clear_bit());
+        self.dma.write.stop();
+        self.dma.read.stop();
+        self.dma.read.clear_flags();
+        self.dma.write.clear_flags();
+        // A calculation cut short leaves results behind, start afresh.
+        self.cordic.config = None;
+    }
 }
 
 impl Cordic {
-    pub fn new(cordic: cordic::Instance) -> Self {
-        Self { cordic }
+    pub fn new(cordic: CORDIC) -> Self {
+        Self { cordic, config: None }
     }
 
-    pub fn init(&self) {
+    pub fn init(&mut self) {
         // Init sin function
-        write_reg!(cordic, self.cordic, CSR, NARGS: Num2, NRES: Num2,
-                                            PRECISION: 0b0101, FUNC: Sine);
+        self.configure(Config::new(Function::Sine));
+    }
+
+    /// Select the function, precision and scale. Skips the register write if
+    /// already configured.
+    ///
+    /// Sine and Cosine are set up to take only the angle argument, with the
+    /// modulus argument held at 1 by a dummy calculation. In packed mode all
+    /// arguments and results take a single register access.
+    pub fn configure(&mut self, config: Config) {
+        if self.config == Some(config) {
+            return;
+        }
+        let (nargs, nres) = if config.packed {
+            (NARGS_A::Num1, NRES_A::Num1)
+        } else {
+            (if config.function.nargs() == 2 { NARGS_A::Num2 } else { NARGS_A::Num1 },
+             if config.function.nres() == 2 { NRES_A::Num2 } else { NRES_A::Num1 })
+        };
+        let (argsize, ressize) = if config.packed {
+            (ARGSIZE_A::Bits16, RESSIZE_A::Bits16)
+        } else {
+            (ARGSIZE_A::Bits32, RESSIZE_A::Bits32)
+        };
+        self.cordic.csr.write(|w| unsafe {
+            w.nargs().variant(nargs).nres().variant(nres)
+             .argsize().variant(argsize).ressize().variant(ressize)
+             .scale().bits(config.scale)
+             .precision().bits(config.precision)
+             .func().variant(config.function.variant())
+        });
 
-        // Dummy calculation
-        write_reg!(cordic, self.cordic, WDATA, 0x80000000);
-        write_reg!(cordic, self.cordic, WDATA, 0x7FFFFFFF);
+        if !config.packed && matches!(config.function, Function::Sine | Function::Cosine) {
+            // Dummy calculation
+            self.write_arg(0x80000000);
+            self.write_arg(0x7FFFFFFF);
 
-        let _dummy = read_reg!(cordic, self.cordic, RDATA);
-        let _dummy = read_reg!(cordic, self.cordic, RDATA);
-        modify_reg!(cordic, self.cordic, CSR, NARGS: Num1);
+            let _dummy = self.read_res();
+            let _dummy = self.read_res();
+            self.cordic.csr.modify(|_, w| w.nargs().variant(NARGS_A::Num1));
+        }
+        self.config = Some(config);
     }
 
-    pub fn calc_sin_cos(&self, theta: f32) -> (f32, f32) {
-        let fxd_input = float_to_q31(theta);
-        write_reg!(cordic, self.cordic, WDATA, fxd_input as u32);
-        let fxd_sin = read_reg!(cordic, self.cordic, RDATA);
-        let fxd_cos = read_reg!(cordic, self.cordic, RDATA);
-        (q31_to_float(fxd_sin as i32), q31_to_float(fxd_cos as i32))
+    fn write_arg(&self, arg: u32) {
+        self.cordic.wdata.write(|w| w.bits(arg));
+    }
+
+    /// Read one result word, stalling the bus until the calculation is done.
+    fn read_res(&self) -> u32 {
+        self.cordic.rdata.read().bits()
+    }
+
+    /// Run one calculation on raw Q1.31 arguments with `config`, returning
+    /// the raw results. Unused trailing arguments and results are ignored.
+    ///
+    /// Must not be used with a packed configuration, see `compute_packed`.
+    pub fn compute(&mut self, config: Config, args: [i32; 2]) -> [i32; 2] {
+        debug_assert!(!config.packed);
+        self.configure(config);
+        for arg in &args[..config.arg_words()] {
+            self.write_arg(*arg as u32);
+        }
+        let mut res = [0; 2];
+        for r in res.iter_mut().take(config.res_words()) {
+            *r = self.read_res() as i32;
+        }
+        res
+    }
+
+    /// Run one calculation on two Q1.15 arguments packed into one word,
+    /// returning both Q1.15 results packed into one word.
+    pub fn compute_packed(&mut self, config: Config, args: u32) -> u32 {
+        self.configure(Config { packed: true, ..config });
+        self.write_arg(args);
+        self.read_res()
+    }
+
+    /// Start running `config` on every calculation in `args` through DMA,
+    /// leaving the CPU free. Arguments and results are laid out as for
+    /// repeated `compute` or `compute_packed` calls, `arg_words` and
+    /// `res_words` per calculation, so a batch of n Q1.31 sines takes n angles
+    /// and gives 2n results.
+    ///
+    /// # Safety
+    /// The DMA keeps accessing `args` and `results` until the returned
+    /// `Batch` is dropped. It must not be leaked, e.g. with `mem::forget`,
+    /// or the transfer outlives the borrows of the buffers.
+    pub unsafe fn start_batch<'a>(&'a mut self, dma: &'a CordicDma, config: Config,
+                                  args: &'a [u32], results: &'a mut [u32]) -> Batch<'a> {
+        let calcs = args.len() / config.arg_words();
+        assert_eq!(calcs * config.arg_words(), args.len(), "Partial argument set");
+        assert_eq!(calcs * config.res_words(), results.len(), "Result buffer size mismatch");
+        assert!(args.len() <= u16::MAX as usize && results.len() <= u16::MAX as usize,
+                "Batch too long for one DMA transfer");
+
+        self.configure(config);
+        let wdata = &self.cordic.wdata as *const _ as u32;
+        let rdata = &self.cordic.rdata as *const _ as u32;
+        dma.write.setup_word_transfer(wdata, args.as_ptr() as u32, args.len() as u16, true);
+        dma.read.setup_word_transfer(rdata, results.as_mut_ptr() as u32, results.len() as u16, false);
+        dma.read.enable();
+        dma.write.enable();
+        self.cordic.csr.modify(|_, w| w.dmawen().set_bit().dmaren().set_bit());
+
+        Batch { cordic: self, dma, _buffers: PhantomData }
+    }
+
+    /// Sine and cosine of `theta`, a Q1.31 angle in units of pi.
+    pub fn calc_sin_cos_q31(&mut self, theta: Q31) -> SinCosQ31 {
+        let [sin, cos] = self.compute(Config::new(Function::Sine), [theta, 0]);
+        SinCosQ31 { sin, cos }
+    }
+
+    /// Sine and cosine of `theta`, a Q1.15 angle in units of pi, with a single
+    /// write and a single read.
+    pub fn calc_sin_cos_q15(&mut self, theta: Q15) -> SinCosQ15 {
+        // Modulus of 1 in the high half-word.
+        let args = 0x7FFF_0000 | theta as u16 as u32;
+        let res = self.compute_packed(Config::new(Function::Sine).packed(), args);
+        SinCosQ15 { sin: res as u16 as i16, cos: (res >> 16) as u16 as i16 }
     }
 
-    pub fn calc_sin_cos_deferred(&self, theta: f32) {
+    /// Sine and cosine of `theta`, in units of pi. `theta` must be in [-1, 1);
+    /// rotor angles should go through `ElecAngle` instead.
+    pub fn calc_sin_cos(&mut self, theta: f32) -> SinCosF32 {
+        let [sin, cos] = self.compute(Config::new(Function::Sine), [float_to_q31(theta), 0]);
+        SinCosF32 { sin: q31_to_float(sin), cos: q31_to_float(cos) }
+    }
+
+    /// Start a sine and cosine calculation, to be collected with `get_result`.
+    pub fn calc_sin_cos_deferred(&mut self, theta: f32) {
+        self.configure(Config::new(Function::Sine));
         let fxd_input = float_to_q31(theta);
-        write_reg!(cordic, self.cordic, WDATA, fxd_input as u32);
+        self.write_arg(fxd_input as u32);
+    }
+
+    pub fn get_result(&self) -> SinCosF32 {
+        let fxd_sin = self.read_res();
+        let fxd_cos = self.read_res();
+        SinCosF32 { sin: q31_to_float(fxd_sin as i32), cos: q31_to_float(fxd_cos as i32) }
+    }
+
+    /// Angle and length of the vector (x, y). Both components must be in
+    /// [-1, 1) and the length below 1.
+    pub fn phase_modulus(&mut self, x: f32, y: f32) -> Polar {
+        let [angle, modulus] = self.compute(Config::new(Function::Phase),
+                                            [float_to_q31(x), float_to_q31(y)]);
+        Polar { angle: q31_to_float(angle), modulus: q31_to_float(modulus) }
+    }
+
+    /// Arctangent of `x` in units of pi, for |x| < 128.
+    pub fn atan(&mut self, x: f32) -> f32 {
+        // Smallest scale bringing x into [-1, 1).
+        let mut scale = 0;
+        while scale < 7 && libm::fabsf(x) >= (1 << scale) as f32 {
+            scale += 1;
+        }
+        let config = Config::new(Function::Arctangent).scale(scale);
+        let [res, _] = self.compute(config, [float_to_q31(x / (1 << scale) as f32), 0]);
+        q31_to_float(res) * (1 << scale) as f32
+    }
+
+    /// Hyperbolic sine and cosine of `x`, for |x| < 1.118.
+    pub fn sinh_cosh(&mut self, x: f32) -> Hyperbolic {
+        let [sinh, cosh] = self.compute(Config::new(Function::HyperbolicSine),
+                                        [float_to_q31(x * 0.5), 0]);
+        Hyperbolic { sinh: 2.0 * q31_to_float(sinh), cosh: 2.0 * q31_to_float(cosh) }
     }
 
-    pub fn get_result(&self) -> (f32, f32) {
-        let fxd_sin = read_reg!(cordic, self.cordic, RDATA);
-        let fxd_cos = read_reg!(cordic, self.cordic, RDATA);
-        (q31_to_float(fxd_sin as i32), q31_to_float(fxd_cos as i32))
+    /// Inverse hyperbolic tangent of `x`, for |x| < 0.806.
+    pub fn atanh(&mut self, x: f32) -> f32 {
+        let [res, _] = self.compute(Config::new(Function::Arctanh), [float_to_q31(x * 0.5), 0]);
+        2.0 * q31_to_float(res)
+    }
+
+    /// Natural logarithm of `x`, for 0.107 <= x < 9.35.
+    pub fn ln(&mut self, x: f32) -> f32 {
+        let scale = match x {
+            x if x < 1.0 => 1,
+            x if x < 3.0 => 2,
+            x if x < 7.0 => 3,
+            _ => 4,
+        };
+        let config = Config::new(Function::NaturalLog).scale(scale);
+        let [res, _] = self.compute(config, [float_to_q31(x / (1 << scale) as f32), 0]);
+        q31_to_float(res) * (2 << scale) as f32
+    }
+
+    /// Square root of `x`, for 0.027 <= x < 2.34.
+    pub fn sqrt(&mut self, x: f32) -> f32 {
+        let scale = match x {
+            x if x < 0.75 => 0,
+            x if x < 1.75 => 1,
+            _ => 2,
+        };
+        let config = Config::new(Function::SquareRoot).scale(scale);
+        let [res, _] = self.compute(config, [float_to_q31(x / (1 << scale) as f32), 0]);
+        q31_to_float(res) * (1 << scale) as f32
+    }
+
+    /// Average cycles per sine/cosine calculation for the Q1.31 and packed
+    /// Q1.15 paths, including argument writes and result reads. The DWT cycle
+    /// counter must be enabled.
+    #[cfg(feature = "bench")]
+    pub fn bench_sin_cos(&mut self) -> (u32, u32) {
+        use core::hint::black_box;
+        use cortex_m::peripheral::DWT;
+        const N: u32 = 1024;
+
+        self.configure(Config::new(Function::Sine));
+        let start = DWT::cycle_count();
+        for i in 0..N {
+            black_box(self.calc_sin_cos_q31(black_box((i << 22) as i32)));
+        }
+        let q31 = DWT::cycle_count().wrapping_sub(start) / N;
+
+        self.configure(Config::new(Function::Sine).packed());
+        let start = DWT::cycle_count();
+        for i in 0..N {
+            black_box(self.calc_sin_cos_q15(black_box((i << 6) as i16)));
+        }
+        let q15 = DWT::cycle_count().wrapping_sub(start) / N;
+
+        (q31, q15)
+    }
+}
+
+impl trig::SinCos for Cordic {
+    fn sin_cos(&mut self, angle: ElecAngle) -> (f32, f32) {
+        let res = self.calc_sin_cos_q31(angle.to_q31());
+        (q31_to_float(res.sin), q31_to_float(res.cos))
     }
 }
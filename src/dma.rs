@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use stm32g4xx_hal::stm32::{DMAMUX, DMA1};
+use stm32g4xx_hal::stm32::{dma1, DMAMUX, DMA1};
 
 /// Driver for the DMAMUX peripheral.
 pub struct DMAMux {
@@ -41,6 +41,7 @@ impl DMAMux {
 }
 
 /// Safe construction of all 8 channels in a DMA peripheral.
+#[allow(clippy::upper_case_acronyms)]
 pub struct DMA {
     pub c1: DMAChannel,
     pub c2: DMAChannel,
@@ -90,29 +91,64 @@ impl DMAChannel {
         DMAChannel { dma, channel }
     }
 
-    //pub fn setup_adc_circ(&self, par0: u32) {
-    //    let channel = self.channel();
-    //    self.dma.ccr1.write(|w| w.en().clear_bit());
-    //    while self.dma.ccr1.read().en().bit_is_set() {}
-    //    self.dma.ccr1.write(|w| w.msize().variant(1).psize().variant(1)
-    //        .minc().set_bit().circ().set_bit().tcie().set_bit());
-    //    self.dma.cpar1.write(|w| w.pa().variant(par0));
-    //}
+    /// Set up a circular transfer of 16-bit items from an ADC data register
+    /// at `par0`, with half-transfer and transfer-complete interrupts.
+    ///
+    /// The channel is left disabled, start it with `start_adc_rx`.
+    pub fn setup_adc_circ(&self, par0: u32) {
+        self.stop();
+        let channel = self.channel();
+        channel.ccr1.write(|w| w.msize().variant(1).psize().variant(1)
+            .minc().set_bit().circ().set_bit().htie().set_bit().tcie().set_bit());
+        channel.cpar1.write(|w| w.pa().variant(par0));
+    }
+
+    /// Start filling `m0ar0` from the start, wrapping around at the end.
+    pub fn start_adc_rx(&self, m0ar0: &mut [u16]) {
+        self.clear_flags();
+        let channel = self.channel();
+        channel.cmar1.write(|w| w.ma().variant(m0ar0.as_mut_ptr() as u32));
+        channel.cndtr1.write(|w| w.ndt().variant(m0ar0.len() as u16));
+        self.enable();
+    }
+
+    /// DMAMUX channel feeding this DMA1 channel.
+    pub fn dmamux_channel(&self) -> u8 {
+        self.channel as u8 - 1
+    }
 
-    //pub fn start_adc_rx(&self, m0ar0: &mut [u16]) {
-    //    self.clear_flags();
-    //    let channel = self.channel();
-    //    self.dma.cmar1.write(|w| w.ma().variant(m0ar0.as_ptr() as u32));
-    //    self.dma.cndtr1.write(|w| w.ndt().variant(m0ar0.len() as u16));
-    //    self.dma.ccr1.modify(|_, w| w.en().set_bit());
-    //}
+    /// Set up a one-shot transfer of `len` 32-bit words between the
+    /// peripheral register at `par` and memory at `mar`, incrementing the
+    /// memory address only. Memory is the source when `to_periph` is set.
+    ///
+    /// The channel is left disabled, start it with `enable`.
+    pub fn setup_word_transfer(&self, par: u32, mar: u32, len: u16, to_periph: bool) {
+        self.stop();
+        self.clear_flags();
+        let channel = self.channel();
+        channel.cpar1.write(|w| w.pa().variant(par));
+        channel.cmar1.write(|w| w.ma().variant(mar));
+        channel.cndtr1.write(|w| w.ndt().variant(len));
+        channel.ccr1.write(|w| w.msize().variant(2).psize().variant(2)
+            .minc().set_bit().dir().bit(to_periph));
+    }
+
+    /// Start the configured transfer.
+    pub fn enable(&self) {
+        self.channel().ccr1.modify(|_, w| w.en().set_bit());
+    }
 
     /// Cancel any ongoing DMA transfer.
-    //pub fn stop(&self) {
-    //    let channel = self.channel();
-    //    self.dma.
-    //    while self.dma.ccr1.read().en().bit_is_set() {}
-    //}
+    pub fn stop(&self) {
+        let channel = self.channel();
+        channel.ccr1.modify(|_, w| w.en().clear_bit());
+        while channel.ccr1.read().en().bit_is_set() {}
+    }
+
+    /// Number of items left to transfer.
+    pub fn remaining(&self) -> u16 {
+        self.channel().cndtr1.read().ndt().bits()
+    }
 
     /// Get the value of the TCIF flag for this channel.
     pub fn tcif(&self) -> bool {
@@ -129,6 +165,21 @@ impl DMAChannel {
         }
     }
 
+    /// Get the value of the HTIF flag for this channel.
+    pub fn htif(&self) -> bool {
+        match self.channel {
+            1 => self.dma.isr.read().htif1().bit_is_set(),
+            2 => self.dma.isr.read().htif2().bit_is_set(),
+            3 => self.dma.isr.read().htif3().bit_is_set(),
+            4 => self.dma.isr.read().htif4().bit_is_set(),
+            5 => self.dma.isr.read().htif5().bit_is_set(),
+            6 => self.dma.isr.read().htif6().bit_is_set(),
+            7 => self.dma.isr.read().htif7().bit_is_set(),
+            8 => self.dma.isr.read().htif8().bit_is_set(),
+            _ => false,
+        }
+    }
+
     /// Get ISR.
     pub fn flags(&self) -> u32 {
         self.dma.isr.read().bits()
@@ -149,6 +200,21 @@ impl DMAChannel {
         }
     }
 
+    /// Clear half-transfer flag for this channel.
+    pub fn clear_htif(&self) {
+        match self.channel {
+            1 => self.dma.ifcr.write(|w| w.htif1().set_bit()),
+            2 => self.dma.ifcr.write(|w| w.htif2().set_bit()),
+            3 => self.dma.ifcr.write(|w| w.htif3().set_bit()),
+            4 => self.dma.ifcr.write(|w| w.htif4().set_bit()),
+            5 => self.dma.ifcr.write(|w| w.htif5().set_bit()),
+            6 => self.dma.ifcr.write(|w| w.htif6().set_bit()),
+            7 => self.dma.ifcr.write(|w| w.htif7().set_bit()),
+            8 => self.dma.ifcr.write(|w| w.htif8().set_bit()),
+            _ => unreachable!(),
+        }
+    }
+
     /// Clear all flags for this channel.
     pub fn clear_flags(&self) {
         match self.channel {
@@ -164,12 +230,13 @@ impl DMAChannel {
         }
     }
 
-    /// Return a special dma::Instance where the 1st channel register
-    /// maps to our specific channel.
+    /// Return a register block where the 1st channel registers map to our
+    /// specific channel.
     ///
     /// Do not access ISR/IFCR through this instance!
-    fn channel(&self) -> &DMA1 {
+    fn channel(&self) -> &dma1::RegisterBlock {
         let ptr = &*self.dma as *const _ as *const u32;
-        unsafe { core::mem::transmute(ptr.offset(5 * (self.channel - 1) as isize)) }
+        // NOTE(unsafe): Channel register sets are 5 words apart.
+        unsafe { &*(ptr.add(5 * (self.channel - 1)) as *const dma1::RegisterBlock) }
     }
 }
@@ -1,25 +1,256 @@
-fn clarke_transform(current_a: f32, current_b: f32) -> (f32, f32) {
-    let current_alpha: f32 = current_a;
-    let current_beta: f32 = (current_a + 2*current_b) / (3.0).sqrt();
-    (current_alpha, current_beta)
+//! Field oriented control transforms.
+//!
+//! All transforms are amplitude invariant: a balanced three-phase set with
+//! peak value `X` maps to an alpha/beta (and d/q) vector of length `X`.
+
+pub mod angle;
+pub mod bus;
+pub mod control;
+pub mod current_sense;
+pub mod deadtime;
+pub mod field_weakening;
+pub mod fixed;
+pub mod limit;
+pub mod mtpa;
+pub mod single_shunt;
+pub mod svpwm;
+pub mod trig;
+
+pub use angle::{ElecAngle, MechAngle};
+pub use trig::SinCos;
+
+const SQRT_3: f32 = 1.732_050_8;
+const FRAC_1_SQRT_3: f32 = 0.577_350_26;
+const FRAC_SQRT_3_2: f32 = 0.866_025_4;
+
+/// Three-phase stationary quantities.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Abc {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+/// Two-phase stationary quantities.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AlphaBeta {
+    pub alpha: f32,
+    pub beta: f32,
 }
 
-fn park_transform(current_alpha: f32, current_beta: f32, angle: f32) -> (f32, f32) {
-    let current_d: f32 = current_alpha * angle.cos() + current_beta * angle.sin();
-    let current_q: f32 = current_beta * angle.cos() - current_alpha * angle.sin();
-    (current_d, current_q)
+/// Two-phase quantities in the rotor reference frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Dq {
+    pub d: f32,
+    pub q: f32,
 }
 
-fn inv_park_transform(current_d: f32, current_q: f32, angle: f32) {
-    let current_alpha: f32 = current_d * angle.cos() - current_q * angle.sin();
-    let current_beta: f32 = current_q * angle.cos() + current_d * angle.sin();
-    (current_alpha, current_beta)
+impl Abc {
+    pub const fn new(a: f32, b: f32, c: f32) -> Self {
+        Self { a, b, c }
+    }
 }
 
-fn svpwm_gen(current_alpha: f32, current_beta: f32, max_pwm: u32) -> (u32, u32, u32) {
-    //TODO Scale everything by max PWM?
-    let pwm_a: u32 = current_alpha;
-    let pwm_b: u32 = (-current_alpha + ((3.0).sqrt() * current_beta)) / 2.0 as u32;
-    let pwm_c: u32 = (-current_alpha - ((3.0).sqrt() * current_beta)) / 2.0 as u32;
-    (pwm_a, pwm_b, pwm_c)
+impl AlphaBeta {
+    pub const fn new(alpha: f32, beta: f32) -> Self {
+        Self { alpha, beta }
+    }
+
+    /// Length of the vector.
+    pub fn magnitude(&self) -> f32 {
+        libm::sqrtf(self.alpha * self.alpha + self.beta * self.beta)
+    }
+}
+
+impl Dq {
+    pub const fn new(d: f32, q: f32) -> Self {
+        Self { d, q }
+    }
+
+    /// Length of the vector.
+    pub fn magnitude(&self) -> f32 {
+        libm::sqrtf(self.d * self.d + self.q * self.q)
+    }
+}
+
+/// Clarke transform of all three phases.
+///
+/// Any common-mode component in the input is discarded.
+pub fn clarke_transform(abc: Abc) -> AlphaBeta {
+    AlphaBeta {
+        alpha: (2.0 * abc.a - abc.b - abc.c) * (1.0 / 3.0),
+        beta: (abc.b - abc.c) * FRAC_1_SQRT_3,
+    }
+}
+
+/// Clarke transform from phases A and B only, assuming `a + b + c = 0`.
+pub fn clarke_transform_ab(a: f32, b: f32) -> AlphaBeta {
+    AlphaBeta {
+        alpha: a,
+        beta: (a + 2.0 * b) * FRAC_1_SQRT_3,
+    }
+}
+
+/// Inverse Clarke transform, producing a set without common-mode component.
+pub fn inv_clarke_transform(ab: AlphaBeta) -> Abc {
+    let half_alpha = -0.5 * ab.alpha;
+    let beta = FRAC_SQRT_3_2 * ab.beta;
+    Abc {
+        a: ab.alpha,
+        b: half_alpha + beta,
+        c: half_alpha - beta,
+    }
+}
+
+/// Park transform by the electrical angle `angle`.
+pub fn park_transform<T: SinCos + ?Sized>(ab: AlphaBeta, angle: ElecAngle, trig: &mut T) -> Dq {
+    let (sin, cos) = trig.sin_cos(angle);
+    park_sin_cos(ab, sin, cos)
+}
+
+/// Inverse Park transform by the electrical angle `angle`.
+pub fn inv_park_transform<T: SinCos + ?Sized>(dq: Dq, angle: ElecAngle, trig: &mut T) -> AlphaBeta {
+    let (sin, cos) = trig.sin_cos(angle);
+    inv_park_sin_cos(dq, sin, cos)
+}
+
+/// Park transform with the sine and cosine of the angle already known.
+pub fn park_sin_cos(ab: AlphaBeta, sin: f32, cos: f32) -> Dq {
+    Dq {
+        d: ab.alpha * cos + ab.beta * sin,
+        q: ab.beta * cos - ab.alpha * sin,
+    }
+}
+
+/// Inverse Park transform with the sine and cosine of the angle already known.
+pub fn inv_park_sin_cos(dq: Dq, sin: f32, cos: f32) -> AlphaBeta {
+    AlphaBeta {
+        alpha: dq.d * cos - dq.q * sin,
+        beta: dq.q * cos + dq.d * sin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trig::{Libm, LookupTable};
+    use super::*;
+    use core::f32::consts::{FRAC_PI_2, FRAC_PI_3, PI};
+
+    const EPS: f32 = 1e-5;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < EPS, "{} != {}", a, b);
+    }
+
+    /// Balanced three-phase set with peak `amp` at electrical angle `theta`.
+    fn balanced(amp: f32, theta: f32) -> Abc {
+        Abc::new(
+            amp * libm::cosf(theta),
+            amp * libm::cosf(theta - 2.0 * PI / 3.0),
+            amp * libm::cosf(theta + 2.0 * PI / 3.0),
+        )
+    }
+
+    #[test]
+    fn clarke_reference_values() {
+        // Phase A at peak: the vector lies on the alpha axis.
+        let ab = clarke_transform(Abc::new(1.0, -0.5, -0.5));
+        assert_close(ab.alpha, 1.0);
+        assert_close(ab.beta, 0.0);
+
+        // Phase B at peak: the vector points to 120 degrees.
+        let ab = clarke_transform(Abc::new(-0.5, 1.0, -0.5));
+        assert_close(ab.alpha, -0.5);
+        assert_close(ab.beta, FRAC_SQRT_3_2);
+
+        // Common mode is rejected.
+        let ab = clarke_transform(Abc::new(3.0, 3.0, 3.0));
+        assert_close(ab.alpha, 0.0);
+        assert_close(ab.beta, 0.0);
+    }
+
+    #[test]
+    fn clarke_two_phase_matches_three_phase() {
+        for i in 0..36 {
+            let abc = balanced(2.5, i as f32 * PI / 18.0);
+            let full = clarke_transform(abc);
+            let two = clarke_transform_ab(abc.a, abc.b);
+            assert_close(full.alpha, two.alpha);
+            assert_close(full.beta, two.beta);
+        }
+    }
+
+    #[test]
+    fn clarke_is_amplitude_invariant() {
+        for i in 0..36 {
+            let theta = i as f32 * PI / 18.0;
+            let ab = clarke_transform(balanced(4.0, theta));
+            assert_close(ab.alpha, 4.0 * libm::cosf(theta));
+            assert_close(ab.beta, 4.0 * libm::sinf(theta));
+            assert_close(ab.magnitude(), 4.0);
+        }
+    }
+
+    #[test]
+    fn inv_clarke_round_trip() {
+        let abc = inv_clarke_transform(AlphaBeta::new(0.3, -0.7));
+        assert_close(abc.a + abc.b + abc.c, 0.0);
+        let ab = clarke_transform(abc);
+        assert_close(ab.alpha, 0.3);
+        assert_close(ab.beta, -0.7);
+    }
+
+    #[test]
+    fn park_reference_values() {
+        // A vector aligned with the rotor is pure d.
+        let dq = park_transform(AlphaBeta::new(0.5, 0.866_025_4), ElecAngle::from_radians(FRAC_PI_3), &mut Libm);
+        assert_close(dq.d, 1.0);
+        assert_close(dq.q, 0.0);
+
+        // A vector 90 degrees ahead of the rotor is pure q.
+        let dq = park_transform(AlphaBeta::new(0.0, 2.0), ElecAngle(0), &mut Libm);
+        assert_close(dq.d, 0.0);
+        assert_close(dq.q, 2.0);
+
+        let dq = park_transform(AlphaBeta::new(1.0, 0.0), ElecAngle::from_radians(FRAC_PI_2), &mut Libm);
+        assert_close(dq.d, 0.0);
+        assert_close(dq.q, -1.0);
+    }
+
+    #[test]
+    fn balanced_set_is_constant_in_rotor_frame() {
+        for i in 0..36 {
+            let theta = i as f32 * PI / 18.0;
+            // Current leading the rotor by 90 degrees.
+            let abc = balanced(3.0, theta + FRAC_PI_2);
+            let dq = park_transform(clarke_transform(abc), ElecAngle::from_radians(theta), &mut Libm);
+            assert_close(dq.d, 0.0);
+            assert_close(dq.q, 3.0);
+        }
+    }
+
+    #[test]
+    fn inv_park_round_trip() {
+        for i in 0..36 {
+            let theta = ElecAngle::from_radians(i as f32 * PI / 18.0 - PI);
+            let dq = Dq::new(-0.4, 1.3);
+            let back = park_transform(inv_park_transform(dq, theta, &mut Libm), theta, &mut Libm);
+            assert_close(back.d, dq.d);
+            assert_close(back.q, dq.q);
+            assert_close(inv_park_transform(dq, theta, &mut Libm).magnitude(), dq.magnitude());
+        }
+    }
+
+    #[test]
+    fn park_backends_agree() {
+        let mut lut = LookupTable::new();
+        for i in 0..100 {
+            let theta = ElecAngle::from_radians(i as f32 * 0.37 - 18.0);
+            let ab = AlphaBeta::new(1.2, -0.8);
+            let reference = park_transform(ab, theta, &mut Libm);
+            let table = park_transform(ab, theta, &mut lut);
+            assert!((reference.d - table.d).abs() < 1e-3);
+            assert!((reference.q - table.q).abs() < 1e-3);
+        }
+    }
 }
@@ -0,0 +1,179 @@
+//! Rotor angles in full-turn fixed point.
+//!
+//! One turn is 2^32 counts, so adding, subtracting and multiplying by the pole
+//! pair count wrap at exactly one turn with no range checks. Reinterpreted as
+//! `i32` the same bits are the Cordic's Q31 angle in units of pi, covering
+//! [-pi, pi).
+
+use core::f32::consts::PI;
+
+use super::fixed::Q31;
+
+/// Counts per turn as a float.
+const TURN: f32 = 4_294_967_296.0;
+
+/// Turn fraction of an angle in radians, in full-turn counts.
+fn radians_to_counts(angle: f32) -> u32 {
+    let turns = angle * (1.0 / (2.0 * PI));
+    let frac = turns - libm::floorf(turns);
+    // Rounding can land exactly on one turn, which wraps to zero.
+    (frac * TURN) as u64 as u32
+}
+
+/// Radians in [-pi, pi) of an angle in full-turn counts.
+fn counts_to_radians(counts: u32) -> f32 {
+    counts as i32 as f32 * (PI / 2_147_483_648.0)
+}
+
+/// Mechanical rotor angle, one turn is 2^32 counts.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MechAngle(pub u32);
+
+/// Electrical rotor angle, one electrical turn is 2^32 counts.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ElecAngle(pub u32);
+
+impl MechAngle {
+    pub fn from_radians(angle: f32) -> Self {
+        Self(radians_to_counts(angle))
+    }
+
+    /// Angle from an MA734 reading, 16 bits per turn.
+    pub const fn from_ma734(raw: u16) -> Self {
+        Self((raw as u32) << 16)
+    }
+
+    /// Radians in [-pi, pi).
+    pub fn to_radians(self) -> f32 {
+        counts_to_radians(self.0)
+    }
+
+    /// Electrical angle of a rotor with `pole_pairs` pole pairs, not counting
+    /// any sensor offset.
+    pub const fn to_electrical(self, pole_pairs: u8) -> ElecAngle {
+        ElecAngle(self.0.wrapping_mul(pole_pairs as u32))
+    }
+
+    /// Angle advanced by `delta` counts.
+    pub const fn wrapping_add(self, delta: i32) -> Self {
+        Self(self.0.wrapping_add(delta as u32))
+    }
+
+    /// Shortest signed distance from `other` to `self`, in counts.
+    pub const fn wrapping_sub(self, other: Self) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+}
+
+impl ElecAngle {
+    pub fn from_radians(angle: f32) -> Self {
+        Self(radians_to_counts(angle))
+    }
+
+    /// Radians in [-pi, pi).
+    pub fn to_radians(self) -> f32 {
+        counts_to_radians(self.0)
+    }
+
+    /// Cordic angle argument, Q31 in units of pi. This is the same bits.
+    pub const fn to_q31(self) -> Q31 {
+        self.0 as i32
+    }
+
+    /// Angle advanced by `delta` counts.
+    pub const fn wrapping_add(self, delta: i32) -> Self {
+        Self(self.0.wrapping_add(delta as u32))
+    }
+
+    /// Shortest signed distance from `other` to `self`, in counts.
+    pub const fn wrapping_sub(self, other: Self) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+}
+
+/// Position sensor mounted on the rotor, with the offset between its zero and
+/// the d-axis.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RotorPosition {
+    pole_pairs: u8,
+    offset: ElecAngle,
+}
+
+impl RotorPosition {
+    pub const fn new(pole_pairs: u8, offset: ElecAngle) -> Self {
+        assert!(pole_pairs > 0, "Motor needs at least one pole pair");
+        Self { pole_pairs, offset }
+    }
+
+    /// Take the offset from a reading made with the rotor held on the d-axis,
+    /// e.g. by driving a d-axis current at electrical angle zero.
+    pub fn align(&mut self, mech: MechAngle) {
+        self.offset = mech.to_electrical(self.pole_pairs);
+    }
+
+    /// Electrical angle of the d-axis for sensor reading `mech`.
+    pub const fn electrical(&self, mech: MechAngle) -> ElecAngle {
+        let raw = mech.to_electrical(self.pole_pairs);
+        ElecAngle(raw.0.wrapping_sub(self.offset.0))
+    }
+
+    pub const fn offset(&self) -> ElecAngle {
+        self.offset
+    }
+
+    pub const fn pole_pairs(&self) -> u8 {
+        self.pole_pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radians_round_trip() {
+        for i in -40..40 {
+            let angle = i as f32 * 0.15;
+            let back = ElecAngle::from_radians(angle).to_radians();
+            let wrapped = libm::remainderf(angle - back, 2.0 * PI);
+            assert!(wrapped.abs() < 1e-5, "{} {}", angle, back);
+            assert!((-PI..PI).contains(&back));
+        }
+        assert_eq!(ElecAngle::from_radians(PI).0, 1 << 31);
+        assert_eq!(ElecAngle::from_radians(-PI / 2.0).0, 3 << 30);
+    }
+
+    #[test]
+    fn q31_is_cordic_format() {
+        // Cordic Q31 angles are in units of pi: 0.5 is a quarter turn.
+        assert_eq!(ElecAngle::from_radians(PI / 2.0).to_q31(), 1 << 30);
+        assert_eq!(ElecAngle::from_radians(-PI / 2.0).to_q31(), -(1 << 30));
+        assert_eq!(ElecAngle(1 << 31).to_q31(), i32::MIN);
+    }
+
+    #[test]
+    fn pole_pairs_and_wrapping() {
+        let mech = MechAngle::from_ma734(0xC000);
+        assert_eq!(mech.0, 3 << 30);
+        // Three quarters of a mechanical turn with 7 pole pairs is 5.25
+        // electrical turns.
+        assert_eq!(mech.to_electrical(7).0, 1 << 30);
+
+        let a = MechAngle(u32::MAX - 10);
+        let b = a.wrapping_add(100);
+        assert_eq!(b.0, 89);
+        assert_eq!(b.wrapping_sub(a), 100);
+        assert_eq!(a.wrapping_sub(b), -100);
+    }
+
+    #[test]
+    fn rotor_offset() {
+        let mut rotor = RotorPosition::new(4, ElecAngle::default());
+        let aligned = MechAngle::from_ma734(0x1234);
+        rotor.align(aligned);
+        assert_eq!(rotor.electrical(aligned), ElecAngle(0));
+        // A quarter of a pole pair later is a quarter electrical turn.
+        let later = aligned.wrapping_add(1 << 27);
+        assert_eq!(rotor.electrical(later), ElecAngle(1 << 29));
+    }
+}
@@ -0,0 +1,87 @@
+//! Bus voltage measurement.
+//!
+//! The modulator divides every voltage command by the bus voltage, so the
+//! current loop gain does not depend on the supply. The measurement is low
+//! pass filtered to keep ADC noise out of the duty cycles while still
+//! following supply sag.
+
+use crate::sense::counts_to_volts;
+
+/// Filtered bus voltage.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BusVoltage {
+    /// Ratio of bus voltage to ADC pin voltage of the input divider.
+    pub divider: f32,
+    alpha: f32,
+    volts: f32,
+    valid: bool,
+}
+
+impl BusVoltage {
+    /// Filter with a cutoff of `cutoff` Hz, updated every `ts` seconds.
+    pub fn new(divider: f32, cutoff: f32, ts: f32) -> Self {
+        let tau = 1.0 / (2.0 * core::f32::consts::PI * cutoff);
+        Self { divider, alpha: ts / (ts + tau), volts: 0.0, valid: false }
+    }
+
+    /// Add a raw divider reading with a full scale of `full_scale` counts,
+    /// with the ADC supply at `vdda` volts.
+    pub fn update_counts(&mut self, counts: u16, full_scale: f32, vdda: f32) -> f32 {
+        self.update(counts_to_volts(counts as f32, full_scale, vdda) * self.divider)
+    }
+
+    /// Add a bus voltage sample in volts. The first sample initialises the
+    /// filter so start-up does not ramp from zero.
+    pub fn update(&mut self, volts: f32) -> f32 {
+        if self.valid {
+            self.volts += self.alpha * (volts - self.volts);
+        } else {
+            self.volts = volts;
+            self.valid = true;
+        }
+        self.volts
+    }
+
+    /// Filtered bus voltage, zero until the first sample.
+    pub fn volts(&self) -> f32 {
+        self.volts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_to_volts() {
+        let mut bus = BusVoltage::new(20.0, 100.0, 100e-6);
+        // Half scale at 3.3 V is 1.65 V at the pin.
+        let v = bus.update_counts(2048, 4095.0, 3.3);
+        assert!((v - 2048.0 / 4095.0 * 3.3 * 20.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn first_sample_initialises() {
+        let mut bus = BusVoltage::new(20.0, 100.0, 100e-6);
+        assert_eq!(bus.volts(), 0.0);
+        assert_eq!(bus.update(24.0), 24.0);
+    }
+
+    #[test]
+    fn follows_supply_sag() {
+        let ts = 100e-6;
+        let mut bus = BusVoltage::new(20.0, 100.0, ts);
+        bus.update(48.0);
+        // One time constant of a 100 Hz filter is 1.59 ms.
+        let steps = (1.0 / (2.0 * core::f32::consts::PI * 100.0) / ts) as usize;
+        for _ in 0..steps {
+            bus.update(36.0);
+        }
+        let dropped = (48.0 - bus.volts()) / 12.0;
+        assert!(dropped > 0.58 && dropped < 0.66, "{}", dropped);
+        for _ in 0..20 * steps {
+            bus.update(36.0);
+        }
+        assert!((bus.volts() - 36.0).abs() < 1e-3);
+    }
+}
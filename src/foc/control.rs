@@ -0,0 +1,331 @@
+//! Discrete PI current control in the rotor reference frame.
+
+use super::Dq;
+
+/// Electrical parameters of the motor, per phase, in SI units.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotorParams {
+    /// Phase resistance in ohms.
+    pub rs: f32,
+    /// d-axis inductance in henries.
+    pub ld: f32,
+    /// q-axis inductance in henries.
+    pub lq: f32,
+    /// Permanent magnet flux linkage in webers (V*s/rad electrical).
+    pub flux_linkage: f32,
+}
+
+/// Gains of a single PI controller.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PiGains {
+    /// Proportional gain in V/A.
+    pub kp: f32,
+    /// Integral gain in V/(A*s).
+    pub ki: f32,
+    /// Back-calculation anti-windup gain in 1/s.
+    pub kb: f32,
+}
+
+impl PiGains {
+    /// Gains placing the closed loop bandwidth of an RL load at `bandwidth`
+    /// rad/s by cancelling the electrical pole. Panics unless `l` and
+    /// `bandwidth` are positive, as the anti-windup gain would not be finite.
+    pub fn from_rl(r: f32, l: f32, bandwidth: f32) -> Self {
+        assert!(bandwidth > 0.0, "Non-positive current loop bandwidth");
+        assert!(l > 0.0, "Non-positive inductance");
+        let kp = l * bandwidth;
+        let ki = r * bandwidth;
+        Self { kp, ki, kb: ki / kp }
+    }
+}
+
+/// PI controller with back-calculation anti-windup.
+///
+/// The integrator only holds the integral term, so changing `kp` at runtime
+/// does not cause an output step.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Pi {
+    pub gains: PiGains,
+    integral: f32,
+}
+
+impl Pi {
+    pub const fn new(gains: PiGains) -> Self {
+        Self { gains, integral: 0.0 }
+    }
+
+    /// Unsaturated output for `error` plus the feedforward term `ff`.
+    pub fn output(&self, error: f32, ff: f32) -> f32 {
+        self.gains.kp * error + self.integral + ff
+    }
+
+    /// Advance the integrator by `dt` seconds. `windup` is the saturated minus
+    /// the unsaturated output of the last step and bleeds the integrator
+    /// while the output is limited.
+    pub fn integrate(&mut self, error: f32, windup: f32, dt: f32) {
+        self.integral += dt * (self.gains.ki * error + self.gains.kb * windup);
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+    }
+}
+
+/// Configuration of the dq current controller.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CurrentControllerConfig {
+    pub d: PiGains,
+    pub q: PiGains,
+    /// Control period in seconds.
+    pub ts: f32,
+    /// Largest output magnitude relative to the bus voltage. `1 / sqrt(3)` is
+    /// the linear modulation limit, `2 / pi` allows full overmodulation.
+    pub max_modulation: f32,
+    /// Motor parameters used by the feedforward terms.
+    pub motor: MotorParams,
+    /// Feed forward the cross-coupling voltages `-w*Lq*iq` and `w*Ld*id`.
+    pub decoupling: bool,
+    /// Feed forward the back-EMF `w*flux_linkage` on the q axis.
+    pub bemf_feedforward: bool,
+}
+
+impl CurrentControllerConfig {
+    /// Configuration with both axes tuned to `bandwidth` rad/s and all
+    /// feedforward terms enabled.
+    pub fn from_motor(motor: MotorParams, bandwidth: f32, ts: f32) -> Self {
+        Self {
+            d: PiGains::from_rl(motor.rs, motor.ld, bandwidth),
+            q: PiGains::from_rl(motor.rs, motor.lq, bandwidth),
+            ts,
+            max_modulation: super::FRAC_1_SQRT_3,
+            motor,
+            decoupling: true,
+            bemf_feedforward: true,
+        }
+    }
+}
+
+/// PI current controller for the d and q axes.
+pub struct CurrentController {
+    pub config: CurrentControllerConfig,
+    d: Pi,
+    q: Pi,
+    output: Dq,
+    saturated: bool,
+}
+
+impl CurrentController {
+    pub fn new(config: CurrentControllerConfig) -> Self {
+        Self {
+            config,
+            d: Pi::new(config.d),
+            q: Pi::new(config.q),
+            output: Dq::default(),
+            saturated: false,
+        }
+    }
+
+    /// Run one control step.
+    ///
+    /// `omega` is the electrical speed in rad/s and `v_bus` the measured bus
+    /// voltage. Returns the voltage request, limited to a circle of radius
+    /// `max_modulation * v_bus` with priority given to the d axis.
+    pub fn update(&mut self, reference: Dq, measured: Dq, omega: f32, v_bus: f32) -> Dq {
+        self.d.gains = self.config.d;
+        self.q.gains = self.config.q;
+
+        let motor = &self.config.motor;
+        let mut ff = Dq::default();
+        if self.config.decoupling {
+            ff.d -= omega * motor.lq * measured.q;
+            ff.q += omega * motor.ld * measured.d;
+        }
+        if self.config.bemf_feedforward {
+            ff.q += omega * motor.flux_linkage;
+        }
+
+        let error = Dq::new(reference.d - measured.d, reference.q - measured.q);
+        let request = Dq::new(self.d.output(error.d, ff.d), self.q.output(error.q, ff.q));
+
+        let v_max = self.config.max_modulation * v_bus.max(0.0);
+        let d = request.d.clamp(-v_max, v_max);
+        let q_max = libm::sqrtf(v_max * v_max - d * d);
+        let output = Dq::new(d, request.q.clamp(-q_max, q_max));
+
+        self.d.integrate(error.d, output.d - request.d, self.config.ts);
+        self.q.integrate(error.q, output.q - request.q, self.config.ts);
+        self.saturated = output != request;
+        self.output = output;
+        output
+    }
+
+    /// Report the voltage actually applied after the modulator's voltage
+    /// limiter, so that any further reduction also stops the integrators.
+    pub fn applied(&mut self, applied: Dq, saturated: bool) {
+        let ts = self.config.ts;
+        self.d.integrate(0.0, applied.d - self.output.d, ts);
+        self.q.integrate(0.0, applied.q - self.output.q, ts);
+        self.saturated |= saturated;
+        self.output = applied;
+    }
+
+    /// Output was limited during the last step.
+    pub fn saturated(&self) -> bool {
+        self.saturated
+    }
+
+    /// Last voltage output, after any reported limiting.
+    pub fn output(&self) -> Dq {
+        self.output
+    }
+
+    /// Clear the integrators, e.g. when the output stage is disabled.
+    pub fn reset(&mut self) {
+        self.d.reset();
+        self.q.reset();
+        self.output = Dq::default();
+        self.saturated = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TS: f32 = 25e-6;
+    const BANDWIDTH: f32 = 2.0 * core::f32::consts::PI * 1000.0;
+    const MOTOR: MotorParams = MotorParams { rs: 0.2, ld: 100e-6, lq: 150e-6, flux_linkage: 0.005 };
+
+    /// dq model of the motor, integrated with sub-steps of the control period.
+    struct RlLoad {
+        i: Dq,
+    }
+
+    impl RlLoad {
+        fn step(&mut self, v: Dq, omega: f32) {
+            const SUB: usize = 20;
+            let dt = TS / SUB as f32;
+            for _ in 0..SUB {
+                let did = (v.d - MOTOR.rs * self.i.d + omega * MOTOR.lq * self.i.q) / MOTOR.ld;
+                let diq = (v.q - MOTOR.rs * self.i.q - omega * MOTOR.ld * self.i.d
+                    - omega * MOTOR.flux_linkage) / MOTOR.lq;
+                self.i.d += did * dt;
+                self.i.q += diq * dt;
+            }
+        }
+    }
+
+    fn run(
+        ctrl: &mut CurrentController,
+        load: &mut RlLoad,
+        reference: Dq,
+        omega: f32,
+        v_bus: f32,
+        steps: usize,
+    ) -> [Dq; 2000] {
+        let mut trace = [Dq::default(); 2000];
+        for item in trace.iter_mut().take(steps) {
+            let v = ctrl.update(reference, load.i, omega, v_bus);
+            load.step(v, omega);
+            *item = load.i;
+        }
+        trace
+    }
+
+    #[test]
+    fn step_response_matches_bandwidth() {
+        let mut ctrl = CurrentController::new(CurrentControllerConfig::from_motor(MOTOR, BANDWIDTH, TS));
+        let mut load = RlLoad { i: Dq::default() };
+        let trace = run(&mut ctrl, &mut load, Dq::new(0.0, 10.0), 0.0, 24.0, 400);
+
+        // First order response: 63 % after one time constant.
+        let tau_steps = (1.0 / BANDWIDTH / TS) as usize;
+        let at_tau = trace[tau_steps].q;
+        assert!(at_tau > 5.5 && at_tau < 7.0, "{}", at_tau);
+
+        let peak = trace.iter().take(400).fold(0.0f32, |m, i| m.max(i.q));
+        assert!(peak < 10.2, "overshoot {}", peak);
+        assert!((trace[399].q - 10.0).abs() < 0.05);
+        assert!(trace[399].d.abs() < 0.05);
+        assert!(!ctrl.saturated());
+    }
+
+    #[test]
+    fn decoupling_rejects_speed_disturbance() {
+        let omega = 2000.0;
+        let config = CurrentControllerConfig::from_motor(MOTOR, BANDWIDTH, TS);
+        let mut ctrl = CurrentController::new(config);
+        let mut load = RlLoad { i: Dq::default() };
+        let coupled = run(&mut ctrl, &mut load, Dq::new(0.0, 10.0), omega, 48.0, 400);
+        let coupled_d = coupled.iter().take(400).fold(0.0f32, |m, i| m.max(i.d.abs()));
+
+        let mut plain = CurrentController::new(CurrentControllerConfig {
+            decoupling: false,
+            bemf_feedforward: false,
+            ..config
+        });
+        let mut load = RlLoad { i: Dq::default() };
+        let uncoupled = run(&mut plain, &mut load, Dq::new(0.0, 10.0), omega, 48.0, 400);
+        let uncoupled_d = uncoupled.iter().take(400).fold(0.0f32, |m, i| m.max(i.d.abs()));
+
+        assert!(coupled_d < 0.5 * uncoupled_d, "{} vs {}", coupled_d, uncoupled_d);
+        assert!((coupled[399].q - 10.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn output_limited_by_bus() {
+        let mut ctrl = CurrentController::new(CurrentControllerConfig::from_motor(MOTOR, BANDWIDTH, TS));
+        let mut load = RlLoad { i: Dq::default() };
+        // 1 V bus can drive at most 0.577 V / 0.2 ohm = 2.9 A.
+        run(&mut ctrl, &mut load, Dq::new(-1.0, 10.0), 0.0, 1.0, 1000);
+        assert!(ctrl.saturated());
+        let out = ctrl.output();
+        assert!((out.magnitude() - super::super::FRAC_1_SQRT_3).abs() < 1e-4);
+        // d axis gets what it needs first.
+        assert!((out.d + MOTOR.rs).abs() < 0.02, "{:?}", out);
+    }
+
+    #[test]
+    fn recovers_from_saturation_without_windup() {
+        let mut ctrl = CurrentController::new(CurrentControllerConfig::from_motor(MOTOR, BANDWIDTH, TS));
+        let mut load = RlLoad { i: Dq::default() };
+        // Demand far more than the bus can deliver for a long time.
+        run(&mut ctrl, &mut load, Dq::new(0.0, 20.0), 0.0, 1.0, 2000);
+        assert!(ctrl.saturated());
+        // The integrator must stay near the saturated output.
+        assert!(ctrl.q.integral() < 1.0, "{}", ctrl.q.integral());
+
+        // Drop to a reachable reference: current settles without overshoot
+        // well within a few time constants.
+        let trace = run(&mut ctrl, &mut load, Dq::new(0.0, 1.0), 0.0, 1.0, 400);
+        let min = trace.iter().take(400).fold(f32::MAX, |m, i| m.min(i.q));
+        assert!(min > 0.9, "undershoot {}", min);
+        assert!((trace[399].q - 1.0).abs() < 0.02);
+        assert!(!ctrl.saturated());
+    }
+
+    #[test]
+    fn reported_limit_stops_integration() {
+        let mut ctrl = CurrentController::new(CurrentControllerConfig::from_motor(MOTOR, BANDWIDTH, TS));
+        let mut load = RlLoad { i: Dq::default() };
+        for _ in 0..2000 {
+            let v = ctrl.update(Dq::new(0.0, 20.0), load.i, 0.0, 24.0);
+            // An external limiter allowing only 2 V on q.
+            let applied = Dq::new(v.d, v.q.min(2.0));
+            ctrl.applied(applied, applied != v);
+            load.step(applied, 0.0);
+        }
+        assert!(ctrl.saturated());
+        assert!(ctrl.q.integral() < 3.0, "{}", ctrl.q.integral());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_bandwidth_rejected() {
+        PiGains::from_rl(MOTOR.rs, MOTOR.ld, 0.0);
+    }
+}
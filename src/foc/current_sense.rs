@@ -0,0 +1,136 @@
+//! Phase current reconstruction from two shunts.
+//!
+//! The board measures two phase currents, one per ADC, and the third follows
+//! from `a + b + c = 0`. Which phase each shunt sits in and the polarity of
+//! its amplifier are configuration, so a swapped motor lead or a reversed
+//! amplifier does not need code changes.
+
+use super::Abc;
+
+/// Motor phase.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    A,
+    B,
+    C,
+}
+
+/// Placement of one current shunt.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Shunt {
+    /// Phase whose current flows through the shunt.
+    pub phase: Phase,
+    /// The amplifier output falls for current flowing into the motor.
+    pub inverted: bool,
+}
+
+/// Two-shunt current sense configuration.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CurrentSense {
+    /// Shunts in measurement order: ADC1 (OPAMP1) then ADC2 (OPAMP2).
+    shunts: [Shunt; 2],
+    /// Phase derived from the other two.
+    derived: Phase,
+}
+
+impl CurrentSense {
+    /// I_A on ADC1 channel 13 through OPAMP1, I_B on ADC2 channel 16 through
+    /// OPAMP2, phase C derived.
+    pub const BOARD: Self = Self::new([
+        Shunt { phase: Phase::A, inverted: false },
+        Shunt { phase: Phase::B, inverted: false },
+    ]);
+
+    /// Panics if both shunts are assigned to the same phase.
+    pub const fn new(shunts: [Shunt; 2]) -> Self {
+        let derived = match (shunts[0].phase, shunts[1].phase) {
+            (Phase::A, Phase::B) | (Phase::B, Phase::A) => Phase::C,
+            (Phase::A, Phase::C) | (Phase::C, Phase::A) => Phase::B,
+            (Phase::B, Phase::C) | (Phase::C, Phase::B) => Phase::A,
+            _ => panic!("Both shunts assigned to the same phase"),
+        };
+        Self { shunts, derived }
+    }
+
+    pub fn shunts(&self) -> [Shunt; 2] {
+        self.shunts
+    }
+
+    /// Phase whose current is not measured.
+    pub fn derived(&self) -> Phase {
+        self.derived
+    }
+
+    /// Build the three phase currents from the two shunt readings, in amps
+    /// and in measurement order. Positive current flows into the motor.
+    pub fn reconstruct(&self, readings: [f32; 2]) -> Abc {
+        let mut abc = Abc::default();
+        let mut sum = 0.0;
+        for (shunt, reading) in self.shunts.iter().zip(readings) {
+            let current = if shunt.inverted { -reading } else { reading };
+            *phase_mut(&mut abc, shunt.phase) = current;
+            sum += current;
+        }
+        *phase_mut(&mut abc, self.derived) = -sum;
+        abc
+    }
+}
+
+fn phase_mut(abc: &mut Abc, phase: Phase) -> &mut f32 {
+    match phase {
+        Phase::A => &mut abc.a,
+        Phase::B => &mut abc.b,
+        Phase::C => &mut abc.c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn board_mapping() {
+        let sense = CurrentSense::BOARD;
+        assert_eq!(sense.derived(), Phase::C);
+        assert_eq!(sense.reconstruct([1.5, -0.5]), Abc::new(1.5, -0.5, -1.0));
+    }
+
+    #[test]
+    fn swapped_shunts_and_derived_phase() {
+        let sense = CurrentSense::new([
+            Shunt { phase: Phase::C, inverted: false },
+            Shunt { phase: Phase::A, inverted: false },
+        ]);
+        assert_eq!(sense.derived(), Phase::B);
+        assert_eq!(sense.reconstruct([2.0, 1.0]), Abc::new(1.0, -3.0, 2.0));
+    }
+
+    #[test]
+    fn inverted_amplifier() {
+        let sense = CurrentSense::new([
+            Shunt { phase: Phase::A, inverted: true },
+            Shunt { phase: Phase::B, inverted: false },
+        ]);
+        assert_eq!(sense.reconstruct([1.5, -0.5]), Abc::new(-1.5, -0.5, 2.0));
+    }
+
+    #[test]
+    fn reconstruction_sums_to_zero() {
+        let sense = CurrentSense::new([
+            Shunt { phase: Phase::B, inverted: true },
+            Shunt { phase: Phase::C, inverted: true },
+        ]);
+        let abc = sense.reconstruct([0.3, 1.7]);
+        assert_eq!(abc.a + abc.b + abc.c, 0.0);
+        assert_eq!(abc.a, 2.0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn same_phase_rejected() {
+        CurrentSense::new([
+            Shunt { phase: Phase::A, inverted: false },
+            Shunt { phase: Phase::A, inverted: true },
+        ]);
+    }
+}
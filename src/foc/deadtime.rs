@@ -0,0 +1,128 @@
+//! Inverter dead-time compensation.
+//!
+//! While both switches of a leg are off the phase voltage follows the current
+//! direction instead of the gate command, so each period loses (or gains) one
+//! dead time of volt-seconds. The compensation adds it back to the duty of
+//! each phase according to the sign of its current.
+
+use super::svpwm::{PhaseDuty, DUTY_MAX};
+use super::Abc;
+
+/// Decode the DTG field of a TIMx_BDTR register into seconds, for a dead-time
+/// clock of `dts_clk` Hz.
+pub fn dtg_to_seconds(dtg: u8, dts_clk: u32) -> f32 {
+    let dtg = dtg as u32;
+    let ticks = match dtg >> 5 {
+        0b000..=0b011 => dtg,
+        0b100 | 0b101 => (64 + (dtg & 0x3F)) * 2,
+        0b110 => (32 + (dtg & 0x1F)) * 8,
+        _ => (32 + (dtg & 0x1F)) * 16,
+    };
+    ticks as f32 / dts_clk as f32
+}
+
+/// Dead-time compensation for one inverter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DeadTimeComp {
+    /// Duty correction at full compensation, as a fraction of the period.
+    gain: f32,
+    /// Current magnitude, in amps, above which the full correction is applied.
+    /// Below it the correction is scaled linearly so it does not chatter when
+    /// the current crosses zero.
+    pub transition_current: f32,
+}
+
+impl DeadTimeComp {
+    /// Compensation for a dead time of `dead_time` seconds inserted once per
+    /// PWM period of `pwm_period` seconds.
+    pub fn new(dead_time: f32, pwm_period: f32, transition_current: f32) -> Self {
+        Self { gain: dead_time / pwm_period, transition_current }
+    }
+
+    /// Duty correction for a phase carrying `current` amps, as a fraction of
+    /// the period. Positive current flows from the inverter into the motor.
+    pub fn correction(&self, current: f32) -> f32 {
+        let polarity = if self.transition_current > 0.0 {
+            (current / self.transition_current).clamp(-1.0, 1.0)
+        } else if current > 0.0 {
+            1.0
+        } else if current < 0.0 {
+            -1.0
+        } else {
+            0.0
+        };
+        self.gain * polarity
+    }
+
+    /// Correct the modulator output for the phase currents `currents`, either
+    /// measured or commanded.
+    pub fn apply(&self, duty: PhaseDuty, currents: Abc) -> PhaseDuty {
+        PhaseDuty {
+            a: self.adjust(duty.a, currents.a),
+            b: self.adjust(duty.b, currents.b),
+            c: self.adjust(duty.c, currents.c),
+            sector: duty.sector,
+        }
+    }
+
+    fn adjust(&self, duty: u32, current: f32) -> u32 {
+        let delta = self.correction(current) * (DUTY_MAX + 1) as f32;
+        (duty as f32 + delta).clamp(0.0, DUTY_MAX as f32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIM_CLK: u32 = 170_000_000;
+
+    #[test]
+    fn dtg_decoding() {
+        // Value used by PwmTim::setup_bldc_pwm.
+        assert!((dtg_to_seconds(85, TIM_CLK) - 500e-9).abs() < 1e-12);
+        assert_eq!(dtg_to_seconds(127, 1_000_000), 127e-6);
+        assert_eq!(dtg_to_seconds(0b1000_0000, 1_000_000), 128e-6);
+        assert_eq!(dtg_to_seconds(0b1011_1111, 1_000_000), 254e-6);
+        assert_eq!(dtg_to_seconds(0b1100_0000, 1_000_000), 256e-6);
+        assert_eq!(dtg_to_seconds(0b1110_0001, 1_000_000), 528e-6);
+        assert_eq!(dtg_to_seconds(0xFF, 1_000_000), 1008e-6);
+    }
+
+    #[test]
+    fn correction_follows_current_sign() {
+        // 500 ns in a 100 us period.
+        let comp = DeadTimeComp::new(500e-9, 100e-6, 0.5);
+        assert!((comp.correction(3.0) - 0.005).abs() < 1e-7);
+        assert!((comp.correction(-3.0) + 0.005).abs() < 1e-7);
+        assert_eq!(comp.correction(0.0), 0.0);
+    }
+
+    #[test]
+    fn correction_is_smooth_near_zero() {
+        let comp = DeadTimeComp::new(500e-9, 100e-6, 0.5);
+        assert!((comp.correction(0.25) - 0.0025).abs() < 1e-7);
+        let mut last = comp.correction(-1.0);
+        for i in -100..=100 {
+            let next = comp.correction(i as f32 / 100.0);
+            assert!(next >= last && next - last < 0.0002);
+            last = next;
+        }
+
+        let hard = DeadTimeComp::new(500e-9, 100e-6, 0.0);
+        assert_eq!(hard.correction(1e-3), 0.005);
+        assert_eq!(hard.correction(-1e-3), -0.005);
+    }
+
+    #[test]
+    fn apply_adjusts_each_phase() {
+        let comp = DeadTimeComp::new(1e-6, 100e-6, 0.1);
+        let duty = PhaseDuty { a: 30000, b: 40000, c: 65500, sector: 3 };
+        let out = comp.apply(duty, Abc::new(2.0, -2.0, 0.05));
+        // 1 % of the period is 655 duty counts.
+        assert_eq!(out.a, 30655);
+        assert_eq!(out.b, 39344);
+        assert_eq!(out.c, DUTY_MAX);
+        assert_eq!(out.sector, 3);
+    }
+}
@@ -0,0 +1,150 @@
+//! Closed-loop field weakening.
+//!
+//! Above base speed the back-EMF approaches the bus voltage and the current
+//! controller runs out of voltage. The controller integrates how far the
+//! voltage limiter's utilisation exceeds a target margin into a negative
+//! d-axis current, which lowers the back-EMF, and shrinks the q-axis current
+//! limit so the total stays on the current circle.
+
+use super::Dq;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FieldWeakeningConfig {
+    /// Integral gain in amps per second per unit of utilisation error.
+    pub ki: f32,
+    /// Voltage utilisation to regulate to, slightly below 1.0 to keep some
+    /// voltage margin for the current controller.
+    pub target: f32,
+    /// Largest demagnetising d-axis current in amps, as a positive value.
+    pub max_demag_current: f32,
+    /// Radius of the current circle in amps.
+    pub max_current: f32,
+    /// Control period in seconds.
+    pub ts: f32,
+}
+
+/// Field-weakening controller placed in front of the current controller.
+pub struct FieldWeakening {
+    pub config: FieldWeakeningConfig,
+    id: f32,
+}
+
+impl FieldWeakening {
+    pub fn new(config: FieldWeakeningConfig) -> Self {
+        Self { config, id: 0.0 }
+    }
+
+    /// Run one step with the `utilisation` reported by the voltage limiter
+    /// during the last step, and adjust the current `reference`.
+    ///
+    /// The field-weakening current is added to the d-axis reference and the
+    /// q-axis reference is limited to what remains of the current circle.
+    /// A non-finite utilisation, as before the first bus voltage sample,
+    /// asks for no further weakening.
+    pub fn update(&mut self, utilisation: f32, reference: Dq) -> Dq {
+        let c = &self.config;
+        let error = if utilisation.is_finite() { utilisation - c.target } else { 0.0 };
+        self.id = (self.id - c.ki * c.ts * error).clamp(-c.max_demag_current, 0.0);
+
+        let d = (reference.d + self.id).clamp(-c.max_current, c.max_current);
+        let q_max = libm::sqrtf(c.max_current * c.max_current - d * d);
+        Dq::new(d, reference.q.clamp(-q_max, q_max))
+    }
+
+    /// Field-weakening d-axis current currently added to the reference.
+    pub fn id(&self) -> f32 {
+        self.id
+    }
+
+    pub fn reset(&mut self) {
+        self.id = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RS: f32 = 0.1;
+    const LD: f32 = 200e-6;
+    const LQ: f32 = 200e-6;
+    const FLUX: f32 = 0.01;
+    const V_MAX: f32 = 24.0 * 0.577_350_26;
+
+    const CONFIG: FieldWeakeningConfig = FieldWeakeningConfig {
+        ki: 500.0,
+        target: 0.95,
+        max_demag_current: 30.0,
+        max_current: 40.0,
+        ts: 100e-6,
+    };
+
+    /// Steady-state voltage utilisation for currents `i` at speed `omega`,
+    /// assuming the current controller tracks the reference.
+    fn utilisation(i: Dq, omega: f32) -> f32 {
+        let vd = RS * i.d - omega * LQ * i.q;
+        let vq = RS * i.q + omega * (LD * i.d + FLUX);
+        Dq::new(vd, vq).magnitude() / V_MAX
+    }
+
+    fn settle(fw: &mut FieldWeakening, reference: Dq, omega: f32) -> Dq {
+        let mut i = Dq::default();
+        for _ in 0..20_000 {
+            i = fw.update(utilisation(i, omega), reference);
+        }
+        i
+    }
+
+    #[test]
+    fn inactive_below_base_speed() {
+        let mut fw = FieldWeakening::new(CONFIG);
+        let i = settle(&mut fw, Dq::new(0.0, 20.0), 500.0);
+        assert_eq!(fw.id(), 0.0);
+        assert_eq!(i, Dq::new(0.0, 20.0));
+    }
+
+    #[test]
+    fn regulates_voltage_margin_above_base_speed() {
+        let mut fw = FieldWeakening::new(CONFIG);
+        // Back-EMF alone would need 1.5x the available voltage.
+        let omega = 1.5 * V_MAX / FLUX;
+        let i = settle(&mut fw, Dq::new(0.0, 10.0), omega);
+        assert!(fw.id() < 0.0);
+        assert!((utilisation(i, omega) - CONFIG.target).abs() < 0.01);
+        assert_eq!(i.q, 10.0);
+    }
+
+    #[test]
+    fn q_current_follows_current_circle() {
+        let mut fw = FieldWeakening::new(CONFIG);
+        let omega = 2.0 * V_MAX / FLUX;
+        let i = settle(&mut fw, Dq::new(0.0, 40.0), omega);
+        assert!(i.d < -10.0);
+        assert!((i.magnitude() - CONFIG.max_current).abs() < 1e-3);
+    }
+
+    #[test]
+    fn demagnetising_current_is_limited() {
+        let mut fw = FieldWeakening::new(CONFIG);
+        let i = settle(&mut fw, Dq::new(0.0, 10.0), 20.0 * V_MAX / FLUX);
+        assert_eq!(fw.id(), -CONFIG.max_demag_current);
+        assert_eq!(i.d, -CONFIG.max_demag_current);
+
+        fw.reset();
+        assert_eq!(fw.id(), 0.0);
+    }
+
+    #[test]
+    fn non_finite_utilisation_ignored() {
+        let mut fw = FieldWeakening::new(CONFIG);
+        for u in [f32::NAN, f32::INFINITY] {
+            let i = fw.update(u, Dq::new(0.0, 10.0));
+            assert_eq!(fw.id(), 0.0);
+            assert_eq!(i, Dq::new(0.0, 10.0));
+        }
+        // Still regulating afterwards.
+        let omega = 1.5 * V_MAX / FLUX;
+        let i = settle(&mut fw, Dq::new(0.0, 10.0), omega);
+        assert!((utilisation(i, omega) - CONFIG.target).abs() < 0.01);
+    }
+}
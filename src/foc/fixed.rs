@@ -0,0 +1,468 @@
+//! Fixed-point FOC pipeline.
+//!
+//! Signals are Q31 per-unit values: currents relative to the current giving a
+//! full-scale ADC reading and voltages relative to a fixed base voltage. The
+//! rotor angle enters as a Q31 sine/cosine pair, the format produced by the
+//! Cordic, and the modulator produces timer compare values directly.
+
+use super::control::PiGains;
+
+/// Signed Q1.31 fraction.
+pub type Q31 = i32;
+/// Signed Q1.15 fraction.
+pub type Q15 = i16;
+
+const Q31_ONE: f32 = 2_147_483_648.0;
+const FRAC_1_SQRT_3_Q31: i64 = 1_239_850_262;
+const FRAC_SQRT_3_2_Q31: i64 = 1_859_775_393;
+
+/// Convert to Q31, saturating values outside [-1, 1).
+pub fn f32_to_q31(x: f32) -> Q31 {
+    // `as` saturates on overflow.
+    libm::roundf(x * Q31_ONE) as i32
+}
+
+pub fn q31_to_f32(x: Q31) -> f32 {
+    x as f32 / Q31_ONE
+}
+
+/// Widen a Q15 value, e.g. a packed Cordic result, to Q31.
+pub const fn q15_to_q31(x: Q15) -> Q31 {
+    (x as i32) << 16
+}
+
+/// Q31 multiply.
+pub const fn q31_mul(a: Q31, b: Q31) -> Q31 {
+    ((a as i64 * b as i64) >> 31) as i32
+}
+
+/// Saturate a wide intermediate result to Q31.
+pub fn sat_q31(x: i64) -> Q31 {
+    x.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+/// Convert a right-aligned ADC reading of `bits` bits, as given by
+/// `Resolution::current_bits`, to a Q31 current, where `offset` is the
+/// reading at zero current.
+pub fn adc_to_q31(raw: u16, offset: u16, bits: u32) -> Q31 {
+    let half = 1 << (bits - 1);
+    (raw as i32 - offset as i32).clamp(-half, half - 1) << (32 - bits)
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AlphaBetaQ31 {
+    pub alpha: Q31,
+    pub beta: Q31,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DqQ31 {
+    pub d: Q31,
+    pub q: Q31,
+}
+
+/// Sine and cosine of the electrical angle.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SinCosQ31 {
+    pub sin: Q31,
+    pub cos: Q31,
+}
+
+/// Timer compare values for the three phases and the active voltage sector.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PhaseCcr {
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+    pub sector: u8,
+}
+
+/// Clarke transform from phases A and B only, assuming `a + b + c = 0`.
+pub fn clarke_transform_ab_q31(a: Q31, b: Q31) -> AlphaBetaQ31 {
+    let beta = (a as i64 + 2 * b as i64) * FRAC_1_SQRT_3_Q31;
+    AlphaBetaQ31 { alpha: a, beta: sat_q31(beta >> 31) }
+}
+
+pub fn park_transform_q31(ab: AlphaBetaQ31, sc: SinCosQ31) -> DqQ31 {
+    let (alpha, beta) = (ab.alpha as i64, ab.beta as i64);
+    let (sin, cos) = (sc.sin as i64, sc.cos as i64);
+    DqQ31 {
+        d: sat_q31((alpha * cos + beta * sin) >> 31),
+        q: sat_q31((beta * cos - alpha * sin) >> 31),
+    }
+}
+
+pub fn inv_park_transform_q31(dq: DqQ31, sc: SinCosQ31) -> AlphaBetaQ31 {
+    let (d, q) = (dq.d as i64, dq.q as i64);
+    let (sin, cos) = (sc.sin as i64, sc.cos as i64);
+    AlphaBetaQ31 {
+        alpha: sat_q31((d * cos - q * sin) >> 31),
+        beta: sat_q31((q * cos + d * sin) >> 31),
+    }
+}
+
+/// Compare value scaling for a bus voltage, worked out once per control
+/// period so the modulator multiplies rather than divides.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PwmScale {
+    v_bus: Q31,
+    arr: u32,
+    /// `arr / v_bus`, in compare counts per Q31 unit shifted by `PWM_SCALE_SHIFT`.
+    k: i32,
+}
+
+const PWM_SCALE_SHIFT: u32 = 40;
+
+impl PwmScale {
+    /// Scaling with the bus at `v_bus`, for a timer with auto-reload `arr`.
+    ///
+    /// The only division of a control step. The factor saturates for a bus
+    /// below `arr` x 2^-22 of the base voltage, far under any operating
+    /// point, and a bus at or below zero holds every phase at 50%.
+    pub fn new(v_bus: Q31, arr: u32) -> Self {
+        let k = if v_bus > 0 {
+            (((arr as u64) << PWM_SCALE_SHIFT) / v_bus as u64).min(i32::MAX as u64) as i32
+        } else {
+            0
+        };
+        Self { v_bus, arr, k }
+    }
+
+    pub fn v_bus(&self) -> Q31 {
+        self.v_bus
+    }
+
+    pub fn arr(&self) -> u32 {
+        self.arr
+    }
+
+    /// Compare value for a phase voltage, one 32x32 bit multiply.
+    fn ccr(&self, v: i64) -> u32 {
+        let ccr = (sat_q31(v) as i64 * self.k as i64) >> PWM_SCALE_SHIFT;
+        (ccr + (self.arr / 2) as i64).clamp(0, self.arr as i64) as u32
+    }
+}
+
+/// Space-vector modulation with min/max injection of `v` into compare values,
+/// scaled by `scale` for the bus voltage and timer.
+pub fn svpwm_q31(v: AlphaBetaQ31, scale: &PwmScale) -> PhaseCcr {
+    let alpha = v.alpha as i64;
+    let beta = v.beta as i64;
+    let sector = sector_q31(alpha, beta);
+
+    let beta_term = (beta * FRAC_SQRT_3_2_Q31) >> 31;
+    let a = alpha;
+    let b = -(alpha >> 1) + beta_term;
+    let c = -(alpha >> 1) - beta_term;
+
+    let offset = -((a.max(b).max(c) + a.min(b).min(c)) >> 1);
+    PhaseCcr { a: scale.ccr(a + offset), b: scale.ccr(b + offset), c: scale.ccr(c + offset), sector }
+}
+
+/// Same numbering as `svpwm::sector`.
+fn sector_q31(alpha: i64, beta: i64) -> u8 {
+    const SECTORS: [u8; 8] = [1, 2, 6, 1, 4, 3, 5, 1];
+    let x = beta;
+    let y = ((alpha * FRAC_SQRT_3_2_Q31) >> 31) - beta / 2;
+    let z = -((alpha * FRAC_SQRT_3_2_Q31) >> 31) - beta / 2;
+    SECTORS[(x > 0) as usize | ((y > 0) as usize) << 1 | ((z > 0) as usize) << 2]
+}
+
+/// Integer square root, exact to 16 significant bits.
+///
+/// Normalised with a leading zero count to 32 bits, so the Newton steps run
+/// on the hardware divider, and typically take four iterations.
+fn isqrt(x: u64) -> u64 {
+    // Even, so the root shifts back by half.
+    let bits = 64 - x.leading_zeros();
+    let shift = (bits.saturating_sub(32) + 1) & !1;
+    let x32 = (x >> shift) as u32;
+    if x32 == 0 {
+        return 0;
+    }
+
+    // A power of two at or above the root, then decreasing to its floor.
+    let mut root = 1u32 << (32 - x32.leading_zeros()).div_ceil(2);
+    loop {
+        let next = (root + x32 / root) >> 1;
+        if next >= root {
+            break;
+        }
+        root = next;
+    }
+    (root as u64) << (shift / 2)
+}
+
+/// Fixed-point PI controller with back-calculation anti-windup.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PiQ31 {
+    /// Proportional gain in Q16.16.
+    pub kp: i32,
+    /// Integral gain times the control period.
+    pub ki_ts: Q31,
+    /// Anti-windup gain times the control period.
+    pub kb_ts: Q31,
+    integral: Q31,
+}
+
+impl PiQ31 {
+    /// Convert floating point gains in SI units for a loop running every
+    /// `ts` seconds, with the given per-unit base values.
+    pub fn from_gains(gains: PiGains, ts: f32, i_base: f32, v_base: f32) -> Self {
+        let scale = i_base / v_base;
+        Self {
+            kp: libm::roundf(gains.kp * scale * 65536.0) as i32,
+            ki_ts: f32_to_q31(gains.ki * ts * scale),
+            kb_ts: f32_to_q31(gains.kb * ts),
+            integral: 0,
+        }
+    }
+
+    /// Unsaturated output, wide to avoid overflow before limiting.
+    pub fn output(&self, error: Q31) -> i64 {
+        ((self.kp as i64 * error as i64) >> 16) + self.integral as i64
+    }
+
+    pub fn integrate(&mut self, error: Q31, windup: i64) {
+        let windup = sat_q31(windup) as i64;
+        let step = (self.ki_ts as i64 * error as i64 + self.kb_ts as i64 * windup) >> 31;
+        self.integral = sat_q31(self.integral as i64 + step);
+    }
+
+    pub fn integral(&self) -> Q31 {
+        self.integral
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0;
+    }
+}
+
+/// Current loop from phase currents to timer compare values.
+pub struct FocQ31 {
+    pub d: PiQ31,
+    pub q: PiQ31,
+    /// Largest output magnitude relative to the bus voltage.
+    pub max_modulation: Q31,
+    output: DqQ31,
+    saturated: bool,
+}
+
+impl FocQ31 {
+    pub fn new(d: PiQ31, q: PiQ31, max_modulation: Q31) -> Self {
+        Self { d, q, max_modulation, output: DqQ31::default(), saturated: false }
+    }
+
+    /// Run one control step on the phase currents `i_a` and `i_b`.
+    ///
+    /// The voltage request is limited to `max_modulation` times the bus
+    /// voltage in `scale` with d-axis priority, then modulated.
+    pub fn update(
+        &mut self,
+        i_a: Q31,
+        i_b: Q31,
+        sc: SinCosQ31,
+        reference: DqQ31,
+        scale: &PwmScale,
+    ) -> PhaseCcr {
+        let measured = park_transform_q31(clarke_transform_ab_q31(i_a, i_b), sc);
+        let err_d = sat_q31(reference.d as i64 - measured.d as i64);
+        let err_q = sat_q31(reference.q as i64 - measured.q as i64);
+        let req_d = self.d.output(err_d);
+        let req_q = self.q.output(err_q);
+
+        let v_max = q31_mul(self.max_modulation, scale.v_bus().max(0)) as i64;
+        let d = req_d.clamp(-v_max, v_max);
+        let q_max = isqrt((v_max * v_max - d * d) as u64) as i64;
+        let q = req_q.clamp(-q_max, q_max);
+
+        self.d.integrate(err_d, d - req_d);
+        self.q.integrate(err_q, q - req_q);
+        self.saturated = d != req_d || q != req_q;
+        self.output = DqQ31 { d: d as i32, q: q as i32 };
+
+        svpwm_q31(inv_park_transform_q31(self.output, sc), scale)
+    }
+
+    pub fn output(&self) -> DqQ31 {
+        self.output
+    }
+
+    pub fn saturated(&self) -> bool {
+        self.saturated
+    }
+
+    pub fn reset(&mut self) {
+        self.d.reset();
+        self.q.reset();
+        self.output = DqQ31::default();
+        self.saturated = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::control::{CurrentController, CurrentControllerConfig, MotorParams};
+    use super::super::svpwm::{svpwm_gen, Modulation, DUTY_MAX};
+    use super::super::trig::Libm;
+    use super::super::{clarke_transform_ab, inv_park_transform, park_transform, AlphaBeta, Dq, ElecAngle};
+    use super::*;
+
+    const I_BASE: f32 = 20.0;
+    const V_BASE: f32 = 60.0;
+    const ARR: u32 = 4249;
+    const TS: f32 = 25e-6;
+    const MOTOR: MotorParams = MotorParams { rs: 0.2, ld: 100e-6, lq: 100e-6, flux_linkage: 0.005 };
+
+    fn sin_cos(angle: ElecAngle) -> SinCosQ31 {
+        let (sin, cos) = libm::sincosf(angle.to_radians());
+        SinCosQ31 { sin: f32_to_q31(sin), cos: f32_to_q31(cos) }
+    }
+
+    /// Simple deterministic pseudo-random sequence in [-1, 1).
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }
+    }
+
+    #[test]
+    fn q_format_helpers() {
+        assert_eq!(f32_to_q31(0.5), 1 << 30);
+        assert_eq!(f32_to_q31(1.5), i32::MAX);
+        assert_eq!(f32_to_q31(-1.0), i32::MIN);
+        assert_eq!(q31_mul(1 << 30, 1 << 30), 1 << 29);
+        assert_eq!(q15_to_q31(-16384), -(1 << 30));
+        assert_eq!(adc_to_q31(2048, 2048, 12), 0);
+        assert_eq!(adc_to_q31(4095, 0, 12), 2047 << 20);
+        assert_eq!(adc_to_q31(0, 4095, 12), i32::MIN);
+        // The same current 4x oversampled to 14 bits.
+        assert_eq!(adc_to_q31(4 * 2047 + 8192, 8192, 14), 2047 << 20);
+        assert_eq!(adc_to_q31(0, 16383, 14), i32::MIN);
+        assert_eq!(isqrt(1 << 62), 1 << 31);
+        assert_eq!(isqrt(99), 9);
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(u32::MAX as u64), 65535);
+    }
+
+    #[test]
+    fn isqrt_precision() {
+        let mut x = 1u64;
+        while x < 1 << 63 {
+            for y in [x, x + x / 3, x + x / 2 + 7] {
+                let root = isqrt(y);
+                let exact = libm::sqrt(y as f64);
+                assert!(root as f64 <= exact + 1e-9, "{}", y);
+                assert!(root as f64 > exact * (1.0 - 1.0 / 32768.0) - 1.0, "{}", y);
+            }
+            x <<= 1;
+        }
+    }
+
+    #[test]
+    fn transforms_match_float() {
+        let mut rng = Lcg(1);
+        for _ in 0..1000 {
+            let (a, b) = (0.5 * rng.next(), 0.5 * rng.next());
+            let angle = ElecAngle::from_radians(4.0 * rng.next());
+            let sc = sin_cos(angle);
+
+            let ab = clarke_transform_ab(a, b);
+            let ab_q = clarke_transform_ab_q31(f32_to_q31(a), f32_to_q31(b));
+            assert!((q31_to_f32(ab_q.beta) - ab.beta).abs() < 1e-6);
+
+            let dq = park_transform(ab, angle, &mut Libm);
+            let dq_q = park_transform_q31(ab_q, sc);
+            assert!((q31_to_f32(dq_q.d) - dq.d).abs() < 1e-5);
+            assert!((q31_to_f32(dq_q.q) - dq.q).abs() < 1e-5);
+
+            let back = inv_park_transform(dq, angle, &mut Libm);
+            let back_q = inv_park_transform_q31(dq_q, sc);
+            assert!((q31_to_f32(back_q.alpha) - back.alpha).abs() < 1e-5);
+            assert!((q31_to_f32(back_q.beta) - back.beta).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn svpwm_matches_float() {
+        let mut rng = Lcg(7);
+        let v_bus = 24.0;
+        for _ in 0..1000 {
+            let v = AlphaBeta::new(10.0 * rng.next(), 10.0 * rng.next());
+            let duty = svpwm_gen(v, v_bus, Modulation::SpaceVector);
+            let ccr = svpwm_q31(
+                AlphaBetaQ31 { alpha: f32_to_q31(v.alpha / V_BASE), beta: f32_to_q31(v.beta / V_BASE) },
+                &PwmScale::new(f32_to_q31(v_bus / V_BASE), ARR),
+            );
+            assert_eq!(ccr.sector, duty.sector);
+            let idle = svpwm_q31(AlphaBetaQ31::default(), &PwmScale::new(0, ARR));
+            assert_eq!((idle.a, idle.b, idle.c), (ARR / 2, ARR / 2, ARR / 2));
+            for (fixed, float) in [(ccr.a, duty.a), (ccr.b, duty.b), (ccr.c, duty.c)] {
+                let float = (float as u64 * ARR as u64 / (DUTY_MAX as u64 + 1)) as i64;
+                assert!((fixed as i64 - float).abs() <= 1, "{} vs {}", fixed, float);
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_matches_float() {
+        let config = CurrentControllerConfig {
+            decoupling: false,
+            bemf_feedforward: false,
+            ..CurrentControllerConfig::from_motor(MOTOR, 2.0 * core::f32::consts::PI * 1000.0, TS)
+        };
+        let mut float = CurrentController::new(config);
+        let mut fixed = FocQ31::new(
+            PiQ31::from_gains(config.d, TS, I_BASE, V_BASE),
+            PiQ31::from_gains(config.q, TS, I_BASE, V_BASE),
+            f32_to_q31(config.max_modulation),
+        );
+
+        let v_bus = 24.0;
+        let scale = PwmScale::new(f32_to_q31(v_bus / V_BASE), ARR);
+        let omega = 3000.0;
+        let mut i = Dq::default();
+        let mut worst = 0i64;
+        for step in 0..2000 {
+            let angle = ElecAngle::from_radians(omega * TS * step as f32);
+            // Large enough to saturate for a while.
+            let reference = Dq::new(-2.0, if step < 1000 { 12.0 } else { 3.0 });
+
+            // Phase currents from the float plant, quantised like the ADC.
+            let ab = inv_park_transform(i, angle, &mut Libm);
+            let i_a = libm::roundf(ab.alpha / I_BASE * 2048.0);
+            let i_b = libm::roundf((-0.5 * ab.alpha + 0.866_025_4 * ab.beta) / I_BASE * 2048.0);
+            let raw_a = (2048.0 + i_a) as u16;
+            let raw_b = (2048.0 + i_b) as u16;
+
+            let measured = park_transform(
+                clarke_transform_ab(i_a * I_BASE / 2048.0, i_b * I_BASE / 2048.0),
+                angle,
+                &mut Libm,
+            );
+            let v = float.update(reference, measured, omega, v_bus);
+            let duty = svpwm_gen(inv_park_transform(v, angle, &mut Libm), v_bus, Modulation::SpaceVector);
+
+            let ccr = fixed.update(
+                adc_to_q31(raw_a, 2048, 12),
+                adc_to_q31(raw_b, 2048, 12),
+                sin_cos(angle),
+                DqQ31 { d: f32_to_q31(reference.d / I_BASE), q: f32_to_q31(reference.q / I_BASE) },
+                &scale,
+            );
+            assert_eq!(fixed.saturated(), float.saturated(), "step {}", step);
+            for (fixed, float) in [(ccr.a, duty.a), (ccr.b, duty.b), (ccr.c, duty.c)] {
+                let float = (float as u64 * ARR as u64 / (DUTY_MAX as u64 + 1)) as i64;
+                worst = worst.max((fixed as i64 - float).abs());
+            }
+
+            // First order plant in the rotor frame, driven by the float output.
+            i.d += TS * (v.d - MOTOR.rs * i.d) / MOTOR.ld;
+            i.q += TS * (v.q - MOTOR.rs * i.q) / MOTOR.lq;
+        }
+        assert!(worst <= 3, "worst compare error {} counts", worst);
+    }
+}
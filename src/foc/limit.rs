@@ -0,0 +1,387 @@
+//! Voltage vector limiting and overmodulation.
+//!
+//! The inverter can only produce stationary frame vectors inside a hexagon
+//! with apothem `v_bus / sqrt(3)`. The limiter takes the dq voltage requested
+//! by the current controller, fits it into what the inverter can produce and
+//! reports the fundamental that is actually applied so the controller can
+//! stop integrating when the output saturates.
+
+use core::f32::consts::{FRAC_PI_3, FRAC_PI_6, PI};
+
+use super::{inv_park_sin_cos, park_sin_cos, AlphaBeta, Dq, ElecAngle, SinCos, FRAC_1_SQRT_3, FRAC_SQRT_3_2};
+
+/// Start of overmodulation region II as a fraction of the six-step fundamental.
+const REGION_2_START: f32 = 0.9517;
+
+/// Voltage limiting strategy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoltageLimit {
+    /// Limit the magnitude to the circle inscribed in the hexagon. Keeps the
+    /// output sinusoidal at all times.
+    Circle,
+    /// Limit to the hexagon, keeping as much of the d-axis voltage as
+    /// possible and cutting the q-axis voltage first.
+    HexagonDPriority,
+    /// Limit to the hexagon by scaling the vector, preserving its angle.
+    HexagonProportional,
+    /// Overmodulation regions I and II, reaching six-step operation when the
+    /// request reaches the six-step fundamental `2 * v_bus / pi`.
+    Overmodulation,
+}
+
+/// Output of the voltage limiter.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LimitedVoltage {
+    /// Fundamental voltage actually applied, for controller anti-windup.
+    pub dq: Dq,
+    /// Instantaneous stationary frame vector to pass to the modulator. Must
+    /// be modulated with `Modulation::SpaceVector`.
+    pub ab: AlphaBeta,
+    /// The request could not be produced and was reduced.
+    pub saturated: bool,
+    /// Requested magnitude relative to the largest magnitude the strategy can
+    /// produce in that direction. Above 1.0 when saturated.
+    pub utilisation: f32,
+}
+
+/// Fit the requested voltage `v`, at electrical angle `angle`, into
+/// what a bus at `v_bus` volts can produce.
+pub fn limit_voltage<T: SinCos + ?Sized>(
+    v: Dq,
+    angle: ElecAngle,
+    v_bus: f32,
+    mode: VoltageLimit,
+    trig: &mut T,
+) -> LimitedVoltage {
+    let apothem = v_bus.max(0.0) * FRAC_1_SQRT_3;
+    let (sin, cos) = trig.sin_cos(angle);
+    match mode {
+        VoltageLimit::Circle => {
+            let utilisation = ratio(v.magnitude(), apothem);
+            let dq = scale_dq(v, utilisation);
+            LimitedVoltage {
+                dq,
+                ab: inv_park_sin_cos(dq, sin, cos),
+                saturated: utilisation > 1.0,
+                utilisation,
+            }
+        }
+        VoltageLimit::HexagonProportional => {
+            let ab = inv_park_sin_cos(v, sin, cos);
+            let utilisation = ratio(hexagon_projection(ab), apothem);
+            let ab = scale_ab(ab, utilisation);
+            LimitedVoltage {
+                dq: park_sin_cos(ab, sin, cos),
+                ab,
+                saturated: utilisation > 1.0,
+                utilisation,
+            }
+        }
+        VoltageLimit::HexagonDPriority => {
+            let utilisation = ratio(hexagon_projection(inv_park_sin_cos(v, sin, cos)), apothem);
+            let dq = if utilisation > 1.0 {
+                hexagon_d_priority(v, sin, cos, apothem)
+            } else {
+                v
+            };
+            LimitedVoltage {
+                dq,
+                ab: inv_park_sin_cos(dq, sin, cos),
+                saturated: utilisation > 1.0,
+                utilisation,
+            }
+        }
+        VoltageLimit::Overmodulation => overmodulate(v, sin, cos, v_bus.max(0.0)),
+    }
+}
+
+/// Ratio of `value` to `limit`, treating a zero limit as infinitely exceeded.
+fn ratio(value: f32, limit: f32) -> f32 {
+    if limit > 0.0 {
+        value / limit
+    } else if value > 0.0 {
+        f32::INFINITY
+    } else {
+        0.0
+    }
+}
+
+fn scale_dq(v: Dq, utilisation: f32) -> Dq {
+    if utilisation > 1.0 {
+        Dq::new(v.d / utilisation, v.q / utilisation)
+    } else {
+        v
+    }
+}
+
+fn scale_ab(v: AlphaBeta, utilisation: f32) -> AlphaBeta {
+    if utilisation > 1.0 {
+        AlphaBeta::new(v.alpha / utilisation, v.beta / utilisation)
+    } else {
+        v
+    }
+}
+
+/// Largest projection of `v` onto the hexagon side normals. The vector is
+/// inside the hexagon when this does not exceed the apothem.
+fn hexagon_projection(v: AlphaBeta) -> f32 {
+    let x = v.beta.abs();
+    let y = (FRAC_SQRT_3_2 * v.alpha + 0.5 * v.beta).abs();
+    let z = (FRAC_SQRT_3_2 * v.alpha - 0.5 * v.beta).abs();
+    x.max(y).max(z)
+}
+
+/// Clamp `v` into the hexagon, reducing `q` before `d`. `sin` and `cos` are
+/// those of the electrical angle.
+fn hexagon_d_priority(v: Dq, sin: f32, cos: f32, apothem: f32) -> Dq {
+    // Side normals at 30, 90 and 150 degrees, rotated into the rotor frame.
+    let normals = [(0.5, FRAC_SQRT_3_2), (1.0, 0.0), (0.5, -FRAC_SQRT_3_2)]
+        .map(|(sin_phi, cos_phi)| park_sin_cos(AlphaBeta::new(cos_phi, sin_phi), sin, cos))
+        .map(|n| (n.q, n.d));
+
+    // The d-axis voltage alone must fit.
+    let max_nd = normals.iter().fold(0.0f32, |m, (_, nd)| m.max(nd.abs()));
+    let d = v.d.clamp(-apothem / max_nd, apothem / max_nd);
+
+    // Each pair of opposite sides bounds q given d.
+    let mut q = v.q;
+    for (nq, nd) in normals {
+        if nq.abs() < 1e-6 {
+            continue;
+        }
+        let lo = (-apothem - d * nd) / nq;
+        let hi = (apothem - d * nd) / nq;
+        q = q.clamp(lo.min(hi), lo.max(hi));
+    }
+    Dq::new(d, q)
+}
+
+/// Hexagon radius at `theta` radians from the start of a sector.
+fn hexagon_radius(theta: f32, apothem: f32) -> f32 {
+    apothem / libm::cosf(theta - FRAC_PI_6)
+}
+
+/// Average magnitude over a sector of a circle of radius `r` clipped to the
+/// hexagon. This is the fundamental produced in overmodulation region I.
+fn region_1_fundamental(r: f32, apothem: f32) -> f32 {
+    if r <= apothem {
+        return r;
+    }
+    let phi = libm::acosf(apothem / r);
+    let (sin, cos) = libm::sincosf(phi);
+    (6.0 / PI) * (r * (FRAC_PI_6 - phi) + apothem * libm::logf((1.0 + sin) / cos))
+}
+
+/// Radius of the clipped circle whose fundamental is `target`.
+fn region_1_radius(target: f32, apothem: f32) -> f32 {
+    // The fundamental is convex in r with derivative (6 / pi) * (pi / 6 - phi),
+    // so Newton's method from the target converges from above.
+    let mut r = target;
+    for _ in 0..8 {
+        let phi = libm::acosf(apothem / r);
+        let slope = (6.0 / PI) * (FRAC_PI_6 - phi);
+        let step = (region_1_fundamental(r, apothem) - target) / slope.max(1e-3);
+        r -= step;
+        if step.abs() < 1e-4 * apothem {
+            break;
+        }
+    }
+    r
+}
+
+/// Holding angle for overmodulation region II, using Holtz's piecewise
+/// linear approximation in terms of the six-step modulation index `m`.
+fn region_2_holding_angle(m: f32) -> f32 {
+    let angle = if m < 0.98 {
+        6.40 * m - 6.09
+    } else if m < 0.9975 {
+        11.75 * m - 11.34
+    } else {
+        48.96 * m - 48.43
+    };
+    angle.clamp(0.0, FRAC_PI_6)
+}
+
+fn overmodulate(v: Dq, sin: f32, cos: f32, v_bus: f32) -> LimitedVoltage {
+    let apothem = v_bus * FRAC_1_SQRT_3;
+    let six_step = 2.0 * v_bus / PI;
+    let requested = inv_park_sin_cos(v, sin, cos);
+    let r = requested.magnitude();
+    let utilisation = ratio(r, six_step);
+
+    if r <= apothem {
+        return LimitedVoltage { dq: v, ab: requested, saturated: false, utilisation };
+    }
+
+    let saturated = utilisation > 1.0;
+    let dq = scale_dq(v, utilisation);
+
+    // Work within the sector, then rotate back.
+    let mut theta = libm::atan2f(requested.beta, requested.alpha);
+    if theta < 0.0 {
+        theta += 2.0 * PI;
+    }
+    let sector = libm::floorf(theta / FRAC_PI_3).min(5.0);
+    let local = (theta - sector * FRAC_PI_3).clamp(0.0, FRAC_PI_3);
+
+    let (local_out, mag) = if utilisation < REGION_2_START {
+        let radius = region_1_radius(r, apothem);
+        (local, radius.min(hexagon_radius(local, apothem)))
+    } else {
+        let hold = region_2_holding_angle(utilisation);
+        let local_out = if local <= hold {
+            0.0
+        } else if local >= FRAC_PI_3 - hold {
+            FRAC_PI_3
+        } else {
+            (local - hold) / (FRAC_PI_3 - 2.0 * hold) * FRAC_PI_3
+        };
+        (local_out, hexagon_radius(local_out, apothem))
+    };
+
+    let (sin_out, cos_out) = libm::sincosf(sector * FRAC_PI_3 + local_out);
+    LimitedVoltage {
+        dq,
+        ab: AlphaBeta::new(mag * cos_out, mag * sin_out),
+        saturated,
+        utilisation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::foc::trig::Libm;
+    use crate::foc::{inv_park_transform, park_transform};
+
+    const V_BUS: f32 = 24.0;
+
+    fn apothem() -> f32 {
+        V_BUS * FRAC_1_SQRT_3
+    }
+
+    fn six_step() -> f32 {
+        2.0 * V_BUS / PI
+    }
+
+    fn inside_hexagon(v: AlphaBeta) -> bool {
+        hexagon_projection(v) <= apothem() * (1.0 + 1e-4)
+    }
+
+    /// Fundamental of the limiter output over one electrical turn, for a
+    /// request of magnitude `mag` rotating with the rotor.
+    fn fundamental(mag: f32, mode: VoltageLimit) -> f32 {
+        const STEPS: usize = 3600;
+        let mut sum = 0.0;
+        for i in 0..STEPS {
+            let angle = ElecAngle::from_radians(i as f32 * 2.0 * PI / STEPS as f32);
+            let out = limit_voltage(Dq::new(0.0, mag), angle, V_BUS, mode, &mut Libm);
+            sum += park_transform(out.ab, angle, &mut Libm).q;
+        }
+        sum / STEPS as f32
+    }
+
+    #[test]
+    fn small_vectors_pass_through() {
+        let v = Dq::new(-2.0, 5.0);
+        for mode in [
+            VoltageLimit::Circle,
+            VoltageLimit::HexagonDPriority,
+            VoltageLimit::HexagonProportional,
+            VoltageLimit::Overmodulation,
+        ] {
+            let out = limit_voltage(v, ElecAngle::from_radians(0.7), V_BUS, mode, &mut Libm);
+            assert!(!out.saturated);
+            assert!((out.dq.d - v.d).abs() < 1e-5 && (out.dq.q - v.q).abs() < 1e-5);
+            assert!(out.utilisation < 1.0);
+        }
+    }
+
+    #[test]
+    fn circle_limits_magnitude() {
+        let out = limit_voltage(Dq::new(10.0, 20.0), ElecAngle::from_radians(1.0), V_BUS, VoltageLimit::Circle, &mut Libm);
+        assert!(out.saturated);
+        assert!((out.dq.magnitude() - apothem()).abs() < 1e-4);
+        assert!((out.dq.q / out.dq.d - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn hexagon_proportional_reaches_vertex() {
+        // Along a vertex the hexagon extends to 2/3 of the bus.
+        let out = limit_voltage(Dq::new(20.0, 0.0), ElecAngle(0), V_BUS, VoltageLimit::HexagonProportional, &mut Libm);
+        assert!(out.saturated);
+        assert!((out.ab.alpha - 2.0 * V_BUS / 3.0).abs() < 1e-4);
+        assert!(out.ab.beta.abs() < 1e-4);
+    }
+
+    #[test]
+    fn hexagon_d_priority_keeps_d() {
+        for i in 0..24 {
+            let angle = ElecAngle::from_radians(i as f32 * PI / 12.0);
+            let out = limit_voltage(Dq::new(-8.0, 30.0), angle, V_BUS, VoltageLimit::HexagonDPriority, &mut Libm);
+            assert!(out.saturated);
+            assert!((out.dq.d + 8.0).abs() < 1e-4);
+            assert!(out.dq.q > 0.0 && out.dq.q < 30.0);
+            assert!(inside_hexagon(out.ab));
+            // Any larger q would leave the hexagon.
+            let bigger = inv_park_transform(Dq::new(-8.0, out.dq.q + 0.01), angle, &mut Libm);
+            assert!(!inside_hexagon(bigger));
+        }
+    }
+
+    #[test]
+    fn hexagon_d_priority_clamps_d_alone() {
+        let out = limit_voltage(Dq::new(-40.0, 5.0), ElecAngle::from_radians(0.3), V_BUS, VoltageLimit::HexagonDPriority, &mut Libm);
+        assert!(out.saturated);
+        assert!(inside_hexagon(out.ab));
+        assert!(out.dq.d < -apothem() * 0.99);
+    }
+
+    #[test]
+    fn region_1_inversion() {
+        for i in 1..10 {
+            let target = apothem() + (0.9517 * six_step() - apothem()) * i as f32 / 10.0;
+            let r = region_1_radius(target, apothem());
+            assert!((region_1_fundamental(r, apothem()) - target).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn overmodulation_tracks_fundamental() {
+        for m in [0.92, 0.94, 0.96, 0.98, 0.99] {
+            let mag = m * six_step();
+            let got = fundamental(mag, VoltageLimit::Overmodulation);
+            assert!((got - mag).abs() / mag < 0.01, "m={} got {}", m, got / six_step());
+        }
+    }
+
+    #[test]
+    fn overmodulation_stays_inside_hexagon() {
+        for m in [0.93, 0.97, 0.999, 1.2] {
+            for i in 0..72 {
+                let angle = ElecAngle::from_radians(i as f32 * PI / 36.0);
+                let out = limit_voltage(Dq::new(0.0, m * six_step()), angle, V_BUS, VoltageLimit::Overmodulation, &mut Libm);
+                assert!(inside_hexagon(out.ab), "m={} angle={:?} {:?}", m, angle, out);
+                assert_eq!(out.saturated, m > 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn overmodulation_reaches_six_step() {
+        let out = limit_voltage(Dq::new(0.0, 2.0 * six_step()), ElecAngle::from_radians(0.4), V_BUS, VoltageLimit::Overmodulation, &mut Libm);
+        assert!(out.saturated);
+        assert!((out.dq.magnitude() - six_step()).abs() < 1e-3);
+        // Six-step only ever applies the active vectors.
+        let mag = out.ab.magnitude();
+        assert!((mag - 2.0 * V_BUS / 3.0).abs() < 1e-3);
+        assert!((fundamental(1.5 * six_step(), VoltageLimit::Overmodulation) - six_step()).abs() < 0.01 * six_step());
+    }
+
+    #[test]
+    fn no_bus_voltage_saturates() {
+        let out = limit_voltage(Dq::new(0.0, 1.0), ElecAngle(0), 0.0, VoltageLimit::Circle, &mut Libm);
+        assert!(out.saturated);
+        assert_eq!(out.dq, Dq::default());
+    }
+}
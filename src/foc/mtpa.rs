@@ -0,0 +1,206 @@
+//! Maximum torque per ampere reference generation.
+//!
+//! With `Ld != Lq` the motor produces reluctance torque proportional to
+//! `(Ld - Lq) * id * iq`, so the current needed for a torque is smallest
+//! somewhere off the q axis. For the usual interior PM motor (`Ld < Lq`)
+//! that means a negative d-axis current.
+
+use super::control::MotorParams;
+use super::Dq;
+
+/// Analytic MTPA solution for a motor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mtpa {
+    pub motor: MotorParams,
+    pub pole_pairs: f32,
+}
+
+impl Mtpa {
+    pub fn new(motor: MotorParams, pole_pairs: u8) -> Self {
+        Self { motor, pole_pairs: pole_pairs as f32 }
+    }
+
+    fn torque_constant(&self) -> f32 {
+        1.5 * self.pole_pairs
+    }
+
+    /// Shaft torque in Nm produced by the currents `i`, including reluctance
+    /// torque.
+    pub fn torque(&self, i: Dq) -> f32 {
+        let dl = self.motor.ld - self.motor.lq;
+        self.torque_constant() * i.q * (self.motor.flux_linkage + dl * i.d)
+    }
+
+    /// d-axis current on the MTPA trajectory for the q-axis current `iq`.
+    pub fn id_for_iq(&self, iq: f32) -> f32 {
+        let psi = self.motor.flux_linkage;
+        let dl = self.motor.ld - self.motor.lq;
+        // (sqrt(psi^2 + 4 dl^2 iq^2) - psi) / (2 dl), rearranged so it stays
+        // well defined as dl approaches zero.
+        let root = libm::sqrtf(psi * psi + 4.0 * dl * dl * iq * iq);
+        let den = root + psi;
+        if den > 0.0 {
+            2.0 * dl * iq * iq / den
+        } else {
+            0.0
+        }
+    }
+
+    /// Currents on the MTPA trajectory producing `torque` Nm.
+    pub fn currents(&self, torque: f32) -> Dq {
+        let psi = self.motor.flux_linkage;
+        let dl = self.motor.ld - self.motor.lq;
+        let k = self.torque_constant();
+        let target = torque.abs();
+        if target == 0.0 || k <= 0.0 {
+            return Dq::default();
+        }
+
+        // Start from the magnet torque alone and refine with Newton's method.
+        let mut iq = if psi > 0.0 {
+            target / (k * psi)
+        } else {
+            libm::sqrtf(target / (k * dl.abs()))
+        };
+        for _ in 0..8 {
+            let root = libm::sqrtf(psi * psi + 4.0 * dl * dl * iq * iq);
+            let id = self.id_for_iq(iq);
+            let t = k * iq * (psi + dl * id);
+            let slope = k * (psi + dl * id + iq * dl * 2.0 * dl * iq / root);
+            if slope <= 0.0 {
+                break;
+            }
+            let step = (t - target) / slope;
+            iq -= step;
+            if step.abs() <= 1e-6 * iq.abs() {
+                break;
+            }
+        }
+
+        Dq::new(self.id_for_iq(iq), iq.copysign(torque))
+    }
+}
+
+/// MTPA trajectory precomputed at `N` evenly spaced torques and linearly
+/// interpolated, avoiding the square roots and iteration in the control loop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MtpaTable<const N: usize> {
+    max_torque: f32,
+    points: [Dq; N],
+}
+
+impl<const N: usize> MtpaTable<N> {
+    /// Tabulate `mtpa` from zero to `max_torque` Nm, which must be positive.
+    /// Larger requests are clamped to `max_torque`.
+    pub fn new(mtpa: &Mtpa, max_torque: f32) -> Self {
+        assert!(N >= 2, "MTPA table needs at least two points");
+        assert!(max_torque > 0.0, "Non-positive MTPA table torque");
+        let mut points = [Dq::default(); N];
+        for (i, point) in points.iter_mut().enumerate() {
+            *point = mtpa.currents(max_torque * i as f32 / (N - 1) as f32);
+        }
+        Self { max_torque, points }
+    }
+
+    /// Interpolated currents producing `torque` Nm.
+    pub fn currents(&self, torque: f32) -> Dq {
+        let pos = (torque.abs() / self.max_torque).min(1.0) * (N - 1) as f32;
+        let idx = (pos as usize).min(N - 2);
+        let frac = pos - idx as f32;
+        let (lo, hi) = (self.points[idx], self.points[idx + 1]);
+        let d = lo.d + (hi.d - lo.d) * frac;
+        let q = lo.q + (hi.q - lo.q) * frac;
+        Dq::new(d, q.copysign(torque))
+    }
+
+    pub fn max_torque(&self) -> f32 {
+        self.max_torque
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPM: MotorParams = MotorParams { rs: 0.05, ld: 150e-6, lq: 400e-6, flux_linkage: 0.02 };
+    const SPM: MotorParams = MotorParams { rs: 0.05, ld: 200e-6, lq: 200e-6, flux_linkage: 0.02 };
+
+    /// Smallest current magnitude producing `torque`, by sweeping id.
+    fn brute_force_min_current(mtpa: &Mtpa, torque: f32) -> f32 {
+        let dl = mtpa.motor.ld - mtpa.motor.lq;
+        let mut best = f32::MAX;
+        for i in 0..20_000 {
+            let id = -(i as f32) * 0.005;
+            let iq = torque / (mtpa.torque_constant() * (mtpa.motor.flux_linkage + dl * id));
+            best = best.min(Dq::new(id, iq).magnitude());
+        }
+        best
+    }
+
+    #[test]
+    fn surface_motor_uses_q_axis_only() {
+        let mtpa = Mtpa::new(SPM, 7);
+        let i = mtpa.currents(0.84);
+        assert_eq!(i.d, 0.0);
+        assert!((i.q - 0.84 / (1.5 * 7.0 * 0.02)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn interior_motor_produces_requested_torque() {
+        let mtpa = Mtpa::new(IPM, 4);
+        for torque in [0.1, 0.5, 1.0, 2.0, 4.0] {
+            let i = mtpa.currents(torque);
+            assert!(i.d < 0.0);
+            assert!((mtpa.torque(i) - torque).abs() < 1e-4 * torque, "{:?}", i);
+        }
+    }
+
+    #[test]
+    fn interior_motor_current_is_minimal() {
+        let mtpa = Mtpa::new(IPM, 4);
+        for torque in [0.5, 2.0, 4.0] {
+            let i = mtpa.currents(torque);
+            let best = brute_force_min_current(&mtpa, torque);
+            assert!(i.magnitude() <= best * 1.0001, "{} > {}", i.magnitude(), best);
+        }
+    }
+
+    #[test]
+    fn negative_torque_is_symmetric() {
+        let mtpa = Mtpa::new(IPM, 4);
+        let pos = mtpa.currents(2.0);
+        let neg = mtpa.currents(-2.0);
+        assert_eq!(neg.d, pos.d);
+        assert_eq!(neg.q, -pos.q);
+        assert!((mtpa.torque(neg) + 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reluctance_torque_in_estimate() {
+        let mtpa = Mtpa::new(IPM, 4);
+        let magnet = mtpa.torque(Dq::new(0.0, 10.0));
+        let with_id = mtpa.torque(Dq::new(-10.0, 10.0));
+        assert!((magnet - 1.5 * 4.0 * 0.02 * 10.0).abs() < 1e-5);
+        assert!((with_id - magnet - 1.5 * 4.0 * 250e-6 * 100.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn table_matches_analytic() {
+        let mtpa = Mtpa::new(IPM, 4);
+        let table: MtpaTable<33> = MtpaTable::new(&mtpa, 4.0);
+        for i in 0..=100 {
+            let torque = -4.0 + 0.08 * i as f32;
+            let exact = mtpa.currents(torque);
+            let approx = table.currents(torque);
+            assert!((exact.d - approx.d).abs() < 0.05, "{:?} {:?}", exact, approx);
+            assert!((exact.q - approx.q).abs() < 0.05, "{:?} {:?}", exact, approx);
+        }
+        assert_eq!(table.currents(10.0), table.currents(4.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_torque_table_rejected() {
+        MtpaTable::<8>::new(&Mtpa::new(IPM, 4), 0.0);
+    }
+}
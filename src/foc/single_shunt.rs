@@ -0,0 +1,129 @@
+//! Phase current reconstruction from one DC link shunt.
+//!
+//! With center-aligned PWM mode 1 a phase is high while the counter is below
+//! its compare value. Counting up, the phase with the lowest compare value
+//! goes low first, leaving the other two high and the shunt carrying minus
+//! its current. Once the middle phase follows, the shunt carries the current
+//! of the highest phase alone. Sampling once in each of those two windows
+//! gives two phase currents, and the third follows from `a + b + c = 0`.
+//!
+//! Near a sector boundary or at low modulation a window gets too short to
+//! sample in. The compare values are then pushed apart to open it up, which
+//! distorts the applied voltage slightly; the error is not compensated.
+
+use super::Abc;
+
+/// Timing of the shunt samples, in timer counts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SingleShunt {
+    /// Delay from a switching edge to the sample point, covering dead time
+    /// and ringing.
+    pub settle: u32,
+    /// Shortest window that can be sampled in, at least `settle` plus the
+    /// ADC sampling time.
+    pub min_window: u32,
+}
+
+/// Compare values and sample points for one PWM period, and which phases
+/// the two samples will measure.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SamplePlan {
+    /// Compare values to apply, adjusted to leave room for both samples.
+    pub ccr: [u32; 3],
+    /// Counter values of the two samples, counting up.
+    pub sample_points: [u32; 2],
+    /// Phases in order of compare value, lowest first.
+    order: [usize; 3],
+}
+
+impl SingleShunt {
+    pub const fn new(settle: u32, min_window: u32) -> Self {
+        assert!(min_window > settle, "Sample window shorter than the settling time");
+        Self { settle, min_window }
+    }
+
+    /// Plan the samples for compare values `ccr` in a period of `arr`.
+    /// Panics if the period cannot fit two windows.
+    pub fn plan(&self, ccr: [u32; 3], arr: u32) -> SamplePlan {
+        assert!(arr >= 2 * self.min_window, "PWM period too short for two sample windows");
+        let w = self.min_window;
+        let mut order = [0, 1, 2];
+        order.sort_unstable_by_key(|&phase| ccr[phase]);
+        let [low, mid, high] = order;
+
+        // Keep the middle phase where it is if there is room either side,
+        // and push the outer phases away from it.
+        let mut ccr = ccr;
+        ccr[mid] = ccr[mid].clamp(w, arr - w);
+        ccr[low] = ccr[low].min(ccr[mid] - w);
+        ccr[high] = ccr[high].max(ccr[mid] + w);
+
+        SamplePlan {
+            ccr,
+            sample_points: [ccr[low] + self.settle, ccr[mid] + self.settle],
+            order,
+        }
+    }
+}
+
+impl SamplePlan {
+    /// Build the three phase currents from the two shunt readings in amps,
+    /// in sample order. Positive current flows from the bus into the
+    /// inverter, and into the motor for the phases.
+    pub fn reconstruct(&self, readings: [f32; 2]) -> Abc {
+        let [low, mid, high] = self.order;
+        let mut currents = [0.0; 3];
+        currents[low] = -readings[0];
+        currents[high] = readings[1];
+        currents[mid] = readings[0] - readings[1];
+        Abc::new(currents[0], currents[1], currents[2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHUNT: SingleShunt = SingleShunt::new(100, 300);
+
+    #[test]
+    fn wide_windows_unchanged() {
+        let plan = SHUNT.plan([6000, 1000, 3000], 8499);
+        assert_eq!(plan.ccr, [6000, 1000, 3000]);
+        assert_eq!(plan.sample_points, [1100, 3100]);
+        // B lowest, A highest.
+        let abc = plan.reconstruct([2.0, 1.5]);
+        assert_eq!(abc, Abc::new(1.5, -2.0, 0.5));
+        assert_eq!(abc.a + abc.b + abc.c, 0.0);
+    }
+
+    #[test]
+    fn narrow_windows_opened() {
+        // All equal, as at zero voltage.
+        let plan = SHUNT.plan([4250; 3], 8499);
+        let mut sorted = plan.ccr;
+        sorted.sort_unstable();
+        assert_eq!(sorted, [3950, 4250, 4550]);
+        assert_eq!(plan.sample_points, [4050, 4350]);
+
+        // Near the bottom of the period the middle phase moves up.
+        let plan = SHUNT.plan([0, 100, 8000], 8499);
+        assert_eq!(plan.ccr, [0, 300, 8000]);
+
+        // Near the top it moves down.
+        let plan = SHUNT.plan([8400, 8450, 8499], 8499);
+        assert_eq!(plan.ccr, [7899, 8199, 8499]);
+    }
+
+    #[test]
+    fn windows_hold_expected_currents() {
+        // Walk the counter through the period and check the switch states at
+        // each sample point against what the reconstruction assumes.
+        let plan = SHUNT.plan([2000, 5000, 2100], 8499);
+        for (sample, point) in plan.sample_points.iter().enumerate() {
+            let high: [bool; 3] = core::array::from_fn(|phase| *point < plan.ccr[phase]);
+            let count = high.iter().filter(|h| **h).count();
+            assert_eq!(count, 2 - sample, "{:?}", plan);
+        }
+    }
+}
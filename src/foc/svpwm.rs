@@ -0,0 +1,156 @@
+//! Space-vector modulation.
+//!
+//! Turns a stationary frame voltage command into the three duty values
+//! expected by `PwmTim::set_bldc_pwm`.
+
+use super::{inv_clarke_transform, AlphaBeta};
+
+/// Duty value corresponding to a permanently high output.
+pub const DUTY_MAX: u32 = 65535;
+/// Duty value of the zero vector, both switches on for equal time.
+pub const DUTY_MID: u32 = 32768;
+
+/// Zero-sequence injection used by the modulator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Modulation {
+    /// Min/max zero-sequence injection, equivalent to symmetric SVPWM.
+    /// Linear up to `v_bus / sqrt(3)`.
+    SpaceVector,
+    /// Plain sinusoidal PWM. Linear up to `v_bus / 2`.
+    Sinusoidal,
+}
+
+/// Per-phase duty values in `0..=DUTY_MAX` and the active voltage sector.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PhaseDuty {
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+    /// Sector of the voltage vector, 1..=6, each spanning 60 degrees
+    /// starting at the alpha axis.
+    pub sector: u8,
+}
+
+/// Find the 60 degree sector the vector lies in, counting from 1 at the
+/// alpha axis. A zero vector is reported as sector 1.
+pub fn sector(v: AlphaBeta) -> u8 {
+    // Indexed by the sign bits of the vector projected on the three
+    // sector boundaries.
+    const SECTORS: [u8; 8] = [1, 2, 6, 1, 4, 3, 5, 1];
+
+    let x = v.beta;
+    let y = 0.5 * (super::SQRT_3 * v.alpha - v.beta);
+    let z = 0.5 * (-super::SQRT_3 * v.alpha - v.beta);
+
+    let n = (x > 0.0) as usize | ((y > 0.0) as usize) << 1 | ((z > 0.0) as usize) << 2;
+    SECTORS[n]
+}
+
+/// Generate phase duties for the voltage vector `v` with the bus at `v_bus`
+/// volts. Phases that would leave the bus range are clamped.
+pub fn svpwm_gen(v: AlphaBeta, v_bus: f32, modulation: Modulation) -> PhaseDuty {
+    let sector = sector(v);
+    if v_bus.is_nan() || v_bus <= 0.0 {
+        return PhaseDuty { a: DUTY_MID, b: DUTY_MID, c: DUTY_MID, sector };
+    }
+
+    let abc = inv_clarke_transform(v);
+    let offset = match modulation {
+        Modulation::SpaceVector => {
+            let max = abc.a.max(abc.b).max(abc.c);
+            let min = abc.a.min(abc.b).min(abc.c);
+            -0.5 * (max + min)
+        }
+        Modulation::Sinusoidal => 0.0,
+    };
+
+    let scale = 1.0 / v_bus;
+    PhaseDuty {
+        a: to_duty((abc.a + offset) * scale),
+        b: to_duty((abc.b + offset) * scale),
+        c: to_duty((abc.c + offset) * scale),
+        sector,
+    }
+}
+
+/// Convert a phase voltage normalised to the bus, centered on zero, to a duty.
+fn to_duty(v: f32) -> u32 {
+    let duty = (v + 0.5) * (DUTY_MAX + 1) as f32;
+    duty.clamp(0.0, DUTY_MAX as f32) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    fn polar(mag: f32, theta: f32) -> AlphaBeta {
+        AlphaBeta::new(mag * libm::cosf(theta), mag * libm::sinf(theta))
+    }
+
+    fn duty_to_volts(duty: u32, v_bus: f32) -> f32 {
+        duty as f32 / (DUTY_MAX + 1) as f32 * v_bus
+    }
+
+    #[test]
+    fn sector_detection() {
+        for sector_idx in 0..6 {
+            let theta = (sector_idx as f32 + 0.5) * PI / 3.0;
+            assert_eq!(sector(polar(1.0, theta)), sector_idx + 1);
+        }
+        assert_eq!(sector(AlphaBeta::new(0.0, 0.0)), 1);
+    }
+
+    #[test]
+    fn zero_vector_is_half_duty() {
+        for modulation in [Modulation::SpaceVector, Modulation::Sinusoidal] {
+            let duty = svpwm_gen(AlphaBeta::default(), 24.0, modulation);
+            assert_eq!((duty.a, duty.b, duty.c), (32768, 32768, 32768));
+        }
+    }
+
+    #[test]
+    fn line_voltages_are_reproduced() {
+        let v_bus = 24.0;
+        // Just inside the linear SVPWM limit.
+        let mag = 0.99 * v_bus / super::super::SQRT_3;
+        for i in 0..72 {
+            let theta = i as f32 * PI / 36.0;
+            let v = polar(mag, theta);
+            let duty = svpwm_gen(v, v_bus, Modulation::SpaceVector);
+            assert!(duty.a <= DUTY_MAX && duty.b <= DUTY_MAX && duty.c <= DUTY_MAX);
+
+            let want = inv_clarke_transform(v);
+            let va = duty_to_volts(duty.a, v_bus);
+            let vb = duty_to_volts(duty.b, v_bus);
+            let vc = duty_to_volts(duty.c, v_bus);
+            assert!(((va - vb) - (want.a - want.b)).abs() < 0.01);
+            assert!(((vb - vc) - (want.b - want.c)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn space_vector_is_centered() {
+        let duty = svpwm_gen(polar(8.0, 0.3), 24.0, Modulation::SpaceVector);
+        let max = duty.a.max(duty.b).max(duty.c);
+        let min = duty.a.min(duty.b).min(duty.c);
+        assert!(((max + min) as i32 - 65536).abs() <= 2);
+    }
+
+    #[test]
+    fn sinusoidal_clamps_above_linear_range() {
+        // Fits SVPWM but exceeds the sinusoidal range.
+        let v = polar(13.0, 0.0);
+        let sine = svpwm_gen(v, 24.0, Modulation::Sinusoidal);
+        assert_eq!(sine.a, DUTY_MAX);
+        let sv = svpwm_gen(v, 24.0, Modulation::SpaceVector);
+        assert!(sv.a < DUTY_MAX);
+    }
+
+    #[test]
+    fn no_bus_voltage_outputs_zero_vector() {
+        let duty = svpwm_gen(polar(5.0, 1.0), 0.0, Modulation::SpaceVector);
+        assert_eq!((duty.a, duty.b, duty.c), (32768, 32768, 32768));
+        assert_eq!(duty.sector, 1);
+    }
+}
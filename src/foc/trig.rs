@@ -0,0 +1,94 @@
+//! Sine/cosine backends for the transforms.
+//!
+//! The rotor frame transforms only need the sine and cosine of the electrical
+//! angle. Making them generic over where those come from lets the same
+//! control code use the Cordic on the G431 and a software backend in host
+//! tests or on parts without one.
+
+use core::f32::consts::PI;
+
+use super::angle::ElecAngle;
+
+/// Source of sine and cosine values.
+pub trait SinCos {
+    /// Sine and cosine of `angle`.
+    fn sin_cos(&mut self, angle: ElecAngle) -> (f32, f32);
+}
+
+/// Software backend using `libm`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Libm;
+
+impl SinCos for Libm {
+    fn sin_cos(&mut self, angle: ElecAngle) -> (f32, f32) {
+        libm::sincosf(angle.to_radians())
+    }
+}
+
+/// Number of table entries per turn, a power of two.
+const LUT_BITS: u32 = 8;
+const LUT_SIZE: usize = 1 << LUT_BITS;
+
+/// Lookup table backend with linear interpolation, accurate to about 1e-4.
+#[derive(Clone, Debug)]
+pub struct LookupTable {
+    /// One turn of sine, with the first entry repeated at the end.
+    table: [f32; LUT_SIZE + 1],
+}
+
+impl LookupTable {
+    pub fn new() -> Self {
+        let mut table = [0.0; LUT_SIZE + 1];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = libm::sinf(2.0 * PI * i as f32 / LUT_SIZE as f32);
+        }
+        Self { table }
+    }
+
+    /// Interpolated sine of `angle`.
+    fn lookup(&self, angle: ElecAngle) -> f32 {
+        let idx = (angle.0 >> (32 - LUT_BITS)) as usize;
+        let frac = (angle.0 << LUT_BITS) as f32 * (1.0 / 4_294_967_296.0);
+        let (lo, hi) = (self.table[idx], self.table[idx + 1]);
+        lo + (hi - lo) * frac
+    }
+}
+
+impl Default for LookupTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SinCos for LookupTable {
+    fn sin_cos(&mut self, angle: ElecAngle) -> (f32, f32) {
+        // Cosine leads sine by a quarter turn.
+        (self.lookup(angle), self.lookup(angle.wrapping_add(1 << 30)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_table_accuracy() {
+        let mut lut = LookupTable::new();
+        for i in -2000..2000 {
+            let angle = ElecAngle::from_radians(i as f32 * 0.0123);
+            let (sin, cos) = lut.sin_cos(angle);
+            let (sin_ref, cos_ref) = Libm.sin_cos(angle);
+            assert!((sin - sin_ref).abs() < 1e-4, "{:?} {} {}", angle, sin, sin_ref);
+            assert!((cos - cos_ref).abs() < 1e-4, "{:?} {} {}", angle, cos, cos_ref);
+        }
+    }
+
+    #[test]
+    fn lookup_table_exact_points() {
+        let mut lut = LookupTable::new();
+        let (sin, cos) = lut.sin_cos(ElecAngle(0));
+        assert_eq!((sin, cos), (0.0, 1.0));
+        let (sin, cos) = lut.sin_cos(ElecAngle(3 << 30));
+        assert!((sin + 1.0).abs() < 1e-6 && cos.abs() < 1e-6);
+    }
+}
@@ -0,0 +1,8 @@
+//! Hardware independent motor control code.
+//!
+//! Everything in here is `no_std` and free of peripheral access, so it can be
+//! unit tested on the host with `cargo test-host`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod foc;
+pub mod sense;
@@ -6,6 +6,9 @@ mod dma;
 mod opamp;
 mod gpio;
 mod adc;
+mod cordic;
+mod rcc;
+mod spi;
 
 use defmt_rtt as _; // global logger
 use panic_probe as _;
@@ -19,32 +22,214 @@ fn panic() -> ! {
 mod app {
     use stm32g4xx_hal::rcc::Config;
     use stm32g4xx_hal::prelude::*;
-    use stm32g4xx_hal::spi::NoMiso;
     use ma734;
     use crate::tim::PwmTim;
     use crate::opamp::Opamp;
     use crate::gpio;
-    use crate::adc::{Adc1, Adc2};
+    use crate::adc::{self, Adc1, Adc2, Config as AdcConfig, DmaMode, DualAdc, DualMode, Edge,
+                     InjectedTrigger, Interrupt, OversampleRatio, Oversampling, RegularDma,
+                     RegularTrigger, SampleTime, Watchdog, WatchdogEvent};
+    use crate::cordic::Cordic;
+    use crate::dma::{DMAMux, DMA};
+    use crate::rcc;
+    use crate::spi::HalfDuplex;
+    use ufoc::foc::bus::BusVoltage;
+    use ufoc::foc::{Abc, MechAngle};
+    use ufoc::foc::svpwm::DUTY_MID;
+    #[cfg(not(feature = "single-shunt"))]
+    use ufoc::foc::current_sense::CurrentSense;
+    // Named unconditionally, as RTIC keeps the types of `#[cfg]` resources.
+    use ufoc::foc::single_shunt::SamplePlan;
+    #[cfg(feature = "single-shunt")]
+    use ufoc::foc::single_shunt::SingleShunt;
+    use ufoc::sense::offset::{OffsetCalibration, OffsetLimits, OffsetTracker};
+    use ufoc::sense::temperature::{TempLimits, Temperature};
+    use ufoc::sense::{BoardParams, Resolution, Sense};
+
+    /// TIM1 period in timer clocks, one current sample per period.
+    const PWM_PERIOD: u32 = 8500;
+    /// Control period of the center-aligned PWM, in seconds.
+    const CONTROL_TS: f32 = 2.0 * PWM_PERIOD as f32 / 170_000_000.0;
+    /// Bus voltage filter cutoff in Hz.
+    const VM_CUTOFF: f32 = 200.0;
+    /// Current samples per channel for the boot offset calibration.
+    const OFFSET_SAMPLES: usize = 256;
+    /// Time constant of the idle offset tracking in seconds.
+    const OFFSET_TAU: f32 = 10.0;
+    /// Phase current either way that trips the analog watchdogs, in amps.
+    /// Temperature derating lowers the current reference, never this trip.
+    const OVERCURRENT: f32 = 20.0;
+    /// Bus voltage window outside which the analog watchdog trips, in volts.
+    const VM_WINDOW: (f32, f32) = (6.0, 56.0);
+    /// Time constant of the temperature filters in seconds.
+    const TEMP_TAU: f32 = 1.0;
+    /// Regular sequences averaged per DMA half buffer.
+    const REG_DMA_SEQUENCES: usize = 4;
+    /// Length of the regular sequences below.
+    const REG_SEQUENCE_LEN: usize = 8;
+    /// Circular DMA buffer of regular conversions, two halves.
+    const REG_DMA_LEN: usize = 2 * REG_DMA_SEQUENCES * REG_SEQUENCE_LEN;
+
+    /// Oversampling of the slow regular channels: 4 conversions summed to a
+    /// 14-bit result, already finer than the noise on VM and the thermistor.
+    /// The same on both ADCs, so the regular simultaneous conversions stay in
+    /// step. ADC1's sequence, with its 640.5 cycle temperature sensor rank,
+    /// takes about 43us at the 85MHz ADC clock, leaving room in the 100us
+    /// period for the injected conversions that interrupt it.
+    const SLOW_OVERSAMPLING: Oversampling = Oversampling::new(OversampleRatio::X4, 0);
+    /// ADC1 regular sequence: the NTC on PA2 (IN3), then the internal
+    /// temperature sensor in the last rank.
+    const ADC1_REGULAR: [u8; REG_SEQUENCE_LEN] = [3, 3, 3, 3, 3, 3, 3, adc::TEMP_SENSOR_CHANNEL];
+
+    /// ADC1: I_A from OPAMP1 (IN13) injected on TIM1 TRGO, the temperatures
+    /// regular on TRGO2. AWD1 guards I_A.
+    #[cfg(not(feature = "single-shunt"))]
+    const ADC1_CONFIG: AdcConfig = AdcConfig::new()
+        .sample_time(3, SampleTime::Cycles24_5)
+        .sample_time(adc::TEMP_SENSOR_CHANNEL, SampleTime::Cycles640_5)
+        .sample_time(13, SampleTime::Cycles2_5)
+        .regular(&ADC1_REGULAR)
+        .injected(&[13, 13])
+        .regular_oversampling(SLOW_OVERSAMPLING)
+        .regular_trigger(RegularTrigger::Tim1Trgo2, Edge::Rising)
+        .injected_trigger(InjectedTrigger::Tim1Trgo, Edge::Rising)
+        .dma(DmaMode::Circular)
+        .interrupt(Interrupt::InjectedEndOfSequence)
+        .watchdog(Watchdog::Awd1, 13)
+        .interrupt(Interrupt::AnalogWatchdog1)
+        .build();
+    /// ADC2: I_B from OPAMP2 (IN16) injected, VM on PA0 (IN1) regular, slaved
+    /// to ADC1 in dual mode. AWD1 guards I_B and AWD2 VM.
+    #[cfg(not(feature = "single-shunt"))]
+    const ADC2_CONFIG: AdcConfig = AdcConfig::new()
+        .sample_time(1, SampleTime::Cycles24_5)
+        .sample_time(16, SampleTime::Cycles2_5)
+        .regular(&[1; REG_SEQUENCE_LEN])
+        .injected(&[16, 16])
+        .regular_oversampling(SLOW_OVERSAMPLING)
+        .regular_trigger(RegularTrigger::Tim1Trgo2, Edge::Rising)
+        .injected_trigger(InjectedTrigger::Tim1Trgo, Edge::Rising)
+        .dma(DmaMode::Circular)
+        .watchdog(Watchdog::Awd1, 16)
+        .watchdog(Watchdog::Awd2, 1)
+        .interrupt(Interrupt::AnalogWatchdog1)
+        .interrupt(Interrupt::AnalogWatchdog2)
+        .build();
+    #[cfg(not(feature = "single-shunt"))]
+    const DUAL_MODE: DualMode = DualMode::RegularInjected;
+
+    /// Single-shunt sample timing at 170MHz: 500ns dead time and 300ns of
+    /// ringing before a sample, then 2.5 ADC cycles of sampling plus margin.
+    /// The window also keeps the two sample triggers further apart than a
+    /// conversion takes, so neither is missed.
+    #[cfg(feature = "single-shunt")]
+    const SHUNT: SingleShunt = SingleShunt::new(136, 160);
+    /// ADC1: the DC link shunt through OPAMP1 (IN13) injected, one rank at
+    /// each of the two TRGO2 pulses of a period, so JDR1 always holds the
+    /// first sample and JDR2 the second. The temperatures are regular on
+    /// TRGO. AWD1 guards the shunt.
+    #[cfg(feature = "single-shunt")]
+    const ADC1_CONFIG: AdcConfig = AdcConfig::new()
+        .sample_time(3, SampleTime::Cycles24_5)
+        .sample_time(adc::TEMP_SENSOR_CHANNEL, SampleTime::Cycles640_5)
+        .sample_time(13, SampleTime::Cycles2_5)
+        .regular(&ADC1_REGULAR)
+        .injected(&[13, 13])
+        .injected_trigger(InjectedTrigger::Tim1Trgo2, Edge::Rising)
+        .injected_discontinuous()
+        .regular_oversampling(SLOW_OVERSAMPLING)
+        .regular_trigger(RegularTrigger::Tim1Trgo, Edge::Rising)
+        .dma(DmaMode::Circular)
+        .interrupt(Interrupt::InjectedEndOfSequence)
+        .watchdog(Watchdog::Awd1, 13)
+        .interrupt(Interrupt::AnalogWatchdog1)
+        .build();
+    /// ADC2: VM on PA0 (IN1) regular, slaved to ADC1 in dual mode. AWD2
+    /// guards VM.
+    #[cfg(feature = "single-shunt")]
+    const ADC2_CONFIG: AdcConfig = AdcConfig::new()
+        .sample_time(1, SampleTime::Cycles24_5)
+        .regular(&[1; REG_SEQUENCE_LEN])
+        .regular_oversampling(SLOW_OVERSAMPLING)
+        .regular_trigger(RegularTrigger::Tim1Trgo, Edge::Rising)
+        .dma(DmaMode::Circular)
+        .watchdog(Watchdog::Awd2, 1)
+        .interrupt(Interrupt::AnalogWatchdog2)
+        .build();
+    /// ADC1 runs its injected queue alone.
+    #[cfg(feature = "single-shunt")]
+    const DUAL_MODE: DualMode = DualMode::Regular;
+
+    /// Full scale of each measured channel under the configurations above.
+    const RESOLUTION: Resolution = Resolution {
+        current: ADC1_CONFIG.injected_full_scale(),
+        vm: ADC2_CONFIG.regular_full_scale(),
+        ntc: ADC1_CONFIG.regular_full_scale(),
+        vts: ADC1_CONFIG.regular_full_scale(),
+    };
 
     #[shared]
     struct Shared {
+        /// Filtered bus voltage in volts, to normalise voltage commands as
+        /// `svpwm_gen(v_cmd, v_bus, ..)`.
+        v_bus: f32,
+        /// Latest phase currents in amps, for the current loop.
+        i_abc: Abc,
+        /// Power stage and MCU temperatures, and the phase current allowed
+        /// at them. The current reference is clamped to
+        /// `current_limit(OVERCURRENT)`, e.g. as the field weakening
+        /// `max_current`.
+        temperature: Temperature,
+        /// First analog watchdog trip, which turned the outputs off.
+        trip: Option<WatchdogEvent>,
+        /// Set by `reset_fault`, for the ADC interrupt to act on.
+        reset: bool,
     }
 
     #[local]
     struct Local {
+        adcs: DualAdc,
+        adc1_dma: RegularDma,
+        adc2_dma: RegularDma,
+        bus: BusVoltage,
+        cordic: Cordic,
+        sense: Sense,
+        phase_sense: Sense,
+        offsets: OffsetTracker,
+        pwm: PwmTim,
+        /// The boot offset calibration passed, so the outputs may be on.
+        calibrated: bool,
+        /// Sample plan of the period in flight.
+        #[cfg(feature = "single-shunt")]
+        shunt_plan: Option<SamplePlan>,
     }
 
-    #[init]
+    #[init(local = [adc1_buf: [u16; REG_DMA_LEN] = [0; REG_DMA_LEN],
+                    adc2_buf: [u16; REG_DMA_LEN] = [0; REG_DMA_LEN]])]
     fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::println!("Start");
 
+        rcc::setup_power(&ctx.device.RCC, &ctx.device.PWR);
+        rcc::enable_peripherals(&ctx.device.RCC);
         let rcc = ctx.device.RCC.constrain();
-        let mut rcc = rcc.freeze(Config::pll());
+        let mut rcc = rcc.freeze(Config::pll().pll_cfg(rcc::pll_170mhz()));
 
         ctx.core.SCB.enable_icache();
         ctx.core.SCB.enable_dcache(&mut ctx.core.CPUID);
 
-        //TODO:Enable and init cordic
+        let mut cordic = Cordic::new(ctx.device.CORDIC);
+        cordic.init();
+
+        // The cycle counter timestamps current samples.
+        ctx.core.DCB.enable_trace();
+        ctx.core.DWT.enable_cycle_counter();
+
+        #[cfg(feature = "bench")]
+        {
+            let (q31, q15) = cordic.bench_sin_cos();
+            defmt::println!("Cordic sin/cos cycles: Q1.31 {}, Q1.15 {}", q31, q15);
+            defmt::println!("Q31 control step cycles: {}", bench_foc_q31(&mut cordic));
+        }
 
         defmt::println!("Setup Gpio");
         let gpioa = ctx.device.GPIOA.split(&mut rcc);
@@ -56,13 +241,9 @@ mod app {
         let spi1_sck = gpiob.pb3.into_alternate();
         let spi1_data = gpiob.pb5.into_alternate();
 
-        let spi1 = ctx.device.SPI1.spi(
-            (spi1_sck, NoMiso, spi1_data),
-            stm32g4xx_hal::spi::MODE_0,
-            stm32g4xx_hal::spi::DuplexMode::HalfDuplexMode,
-            1.mhz(),
-            &mut rcc,
-        );
+        // 170 MHz / 256, as the MA734 is only read at boot.
+        let mut spi1 = HalfDuplex::new(ctx.device.SPI1, (spi1_sck, spi1_data));
+        spi1.init(0b111);
 
         //Take all needed pins before giving gpio blocks
         gpio::setup(gpioa, gpiof);
@@ -73,48 +254,279 @@ mod app {
         defmt::println!("Setup TIM1PWM");
         let t1 = ctx.device.TIM1;
         let pwmTimer = PwmTim::new(t1);
-        pwmTimer.setup_bldc_pwm(8500);
+        pwmTimer.setup_bldc_pwm(PWM_PERIOD);
         pwmTimer.set_bldc_pwm(0, 0, 0);
 
         let mut encoder = ma734::MA734::new(spi1, nss_pin);
-        let angle = encoder.read_angle().unwrap();
-        defmt::println!("Angle: {}", angle);
+        let angle = MechAngle::from_ma734(encoder.read_angle().unwrap());
+        defmt::println!("Angle: {}", angle.to_radians());
 
         let mut adc1 = Adc1::new(ctx.device.ADC1);
         let mut adc2 = Adc2::new(ctx.device.ADC2);
-        adc1.setup(ctx.device.ADC12_COMMON);
-        adc2.setup();
+        adc::setup_common(ctx.device.ADC12_COMMON, DUAL_MODE);
+        adc1.setup(&ADC1_CONFIG);
+        adc2.setup(&ADC2_CONFIG);
+
+        let sense = Sense::new(BoardParams::BOARD, RESOLUTION, adc1.vdda());
+        // The bus and temperature filters run once per DMA half buffer.
+        let regular_ts = CONTROL_TS * REG_DMA_SEQUENCES as f32;
+        let mut bus = BusVoltage::new(BoardParams::BOARD.vm_divider, VM_CUTOFF, regular_ts);
+        let v_bus = bus.update(sense.bus_volts(adc2.get_avg_reading(1) as f32));
+        let mut temperature = Temperature::new(&sense, adc::internal_sensor_cal(), TempLimits::POWER_STAGE,
+                                               TempLimits::MCU, TEMP_TAU, regular_ts);
+        // The internal sensor needs longer sampling than a single reading
+        // gets, so it starts with the regular sequence.
+        temperature.update_power_stage(adc1.get_avg_reading(3) as f32);
+        defmt::println!("VDDA: {}, bus voltage: {}", sense.vdda(), v_bus);
+        defmt::println!("Power stage: {} C", temperature.power_stage());
 
-        let _zero1 = adc1.get_avg_reading(13);
-        let _zero2 = adc2.get_avg_reading(16);
+        let dma = DMA::new(ctx.device.DMA1);
+        let dmamux = DMAMux::new(ctx.device.DMAMUX);
+        let mut adc1_dma = RegularDma::new(&adc1, &ADC1_CONFIG, &dmamux, dma.c1, ctx.local.adc1_buf);
+        let mut adc2_dma = RegularDma::new(&adc2, &ADC2_CONFIG, &dmamux, dma.c2, ctx.local.adc2_buf);
+        adc1_dma.start();
+        adc2_dma.start();
+
+        // Offsets are measured at 50% duty with the outputs still off, so the
+        // ADC sees the trigger timing and switching noise but no current.
+        pwmTimer.set_bldc_pwm(DUTY_MID, DUTY_MID, DUTY_MID);
+        #[cfg(feature = "single-shunt")]
+        {
+            pwmTimer.setup_shunt_triggers();
+            apply_shunt_plan(&pwmTimer);
+        }
+        // TIM1 runs from the 170MHz core clock.
+        let mut adcs = DualAdc::new(adc1, adc2, 2 * PWM_PERIOD);
+        // The first rank must go to the first sample point. Starting while
+        // counting down puts both points of the next period ahead.
+        #[cfg(feature = "single-shunt")]
+        pwmTimer.wait_counting_down();
+        adcs.start();
 
-        //TODO: Init and enable DMA
+        let limits = OffsetLimits::DEFAULT.scaled(RESOLUTION.current);
+        let mut calibration = OffsetCalibration::<OFFSET_SAMPLES>::new(limits);
+        while !calibration.push(wait_currents(&mut adcs, &pwmTimer)) {}
+        let (offsets, calibrated) = match calibration.finish() {
+            Ok(offsets) => {
+                defmt::println!("Current offsets: {}, {}", offsets[0], offsets[1]);
+                (offsets, true)
+            }
+            Err(fault) => {
+                defmt::println!("Current offset fault: {}", defmt::Debug2Format(&fault));
+                ([limits.mid_rail(); 2], false)
+            }
+        };
 
-        pwmTimer.motor_on();
+        // Watchdog windows go about the measured offsets. A trip turns the
+        // outputs off from the ADC interrupt.
+        let (low, high) = sense.current_window(OVERCURRENT, offsets[0]);
+        adcs.adc1().set_watchdog_window(Watchdog::Awd1, low, high);
+        #[cfg(not(feature = "single-shunt"))]
+        {
+            let (low, high) = sense.current_window(OVERCURRENT, offsets[1]);
+            adcs.adc2().set_watchdog_window(Watchdog::Awd1, low, high);
+        }
+        let (low, high) = sense.bus_volts_window(VM_WINDOW.0, VM_WINDOW.1);
+        adcs.adc2().set_watchdog_window(Watchdog::Awd2, low, high);
 
-        adc1.start();
-        adc2.start();
+        // Leave the outputs off without a good calibration or when already
+        // too hot.
+        if let Some(fault) = temperature.fault() {
+            defmt::println!("Temperature fault: {}", defmt::Debug2Format(&fault));
+        } else if calibrated {
+            pwmTimer.motor_on();
+        }
+        let offsets = OffsetTracker::new(offsets, OFFSET_TAU, CONTROL_TS, limits);
 
         defmt::println!("Init done!");
 
         (Shared {
+            v_bus,
+            i_abc: Abc::default(),
+            temperature,
+            trip: None,
+            reset: false,
         },
 
          Local {
+             adcs,
+             adc1_dma,
+             adc2_dma,
+             bus,
+             cordic,
+             sense,
+             // The current samples keep their own copy.
+             phase_sense: sense,
+             offsets,
+             pwm: pwmTimer,
+             calibrated,
+             // The plan applied during calibration was not kept, so the first
+             // pair only feeds the offsets.
+             #[cfg(feature = "single-shunt")]
+             shunt_plan: None,
          },
 
          init::Monotonics())
     }
 
+    /// Wait for the next current reading of each channel.
+    #[cfg(not(feature = "single-shunt"))]
+    fn wait_currents(adcs: &mut DualAdc, _pwm: &PwmTim) -> [u16; 2] {
+        loop {
+            if let Some(currents) = adcs.take_currents() {
+                return currents.raw;
+            }
+        }
+    }
+
+    /// Wait for both shunt readings of a period and plan the next period.
+    #[cfg(feature = "single-shunt")]
+    fn wait_currents(adcs: &mut DualAdc, pwm: &PwmTim) -> [u16; 2] {
+        loop {
+            if let Some(currents) = adcs.take_shunt_currents() {
+                apply_shunt_plan(pwm);
+                return currents.raw;
+            }
+        }
+    }
+
+    /// Open up the shunt sample windows around the duties in TIM1 and move
+    /// the sample triggers into them, from the next period.
+    #[cfg(feature = "single-shunt")]
+    fn apply_shunt_plan(pwm: &PwmTim) -> SamplePlan {
+        let plan = SHUNT.plan(pwm.bldc_ccr(), pwm.arr());
+        let [ccr1, ccr2, ccr3] = plan.ccr;
+        pwm.set_bldc_ccr(ccr1, ccr2, ccr3);
+        let [first, second] = plan.sample_points;
+        pwm.set_sample_points(first, second);
+        plan
+    }
+
+    /// Re-arm the current and bus voltage watchdogs after a trip.
+    fn rearm_watchdogs(adcs: &DualAdc) {
+        adcs.adc1().clear_watchdog(Watchdog::Awd1);
+        #[cfg(not(feature = "single-shunt"))]
+        adcs.adc2().clear_watchdog(Watchdog::Awd1);
+        adcs.adc2().clear_watchdog(Watchdog::Awd2);
+    }
+
+    /// Average cycles per fixed-point control step: the Cordic sine and
+    /// cosine, the current loop and the modulator. The DWT cycle counter must
+    /// be enabled.
+    #[cfg(feature = "bench")]
+    fn bench_foc_q31(cordic: &mut Cordic) -> u32 {
+        use core::hint::black_box;
+        use cortex_m::peripheral::DWT;
+        use ufoc::foc::control::PiGains;
+        use ufoc::foc::fixed::{DqQ31, FocQ31, PiQ31, PwmScale};
+        const N: u32 = 1024;
+
+        let gains = PiGains { kp: 0.5, ki: 1000.0, kb: 5000.0 };
+        let pi = PiQ31::from_gains(gains, CONTROL_TS, OVERCURRENT, 60.0);
+        let mut foc = FocQ31::new(pi, pi, i32::MAX / 100 * 95);
+        // Large enough to saturate, taking the square root on every step.
+        let reference = DqQ31 { d: -(1 << 28), q: 1 << 30 };
+
+        let start = DWT::cycle_count();
+        for i in 0..N {
+            // The one division, done each period with a fresh bus voltage.
+            let scale = PwmScale::new(black_box(1 << 29), PWM_PERIOD);
+            let sc = cordic.calc_sin_cos_q31(black_box((i << 22) as i32));
+            black_box(foc.update(black_box(1 << 20), black_box(-(1 << 20)), sc, reference, &scale));
+        }
+        DWT::cycle_count().wrapping_sub(start) / N
+    }
+
     #[idle]
     fn idle(_: idle::Context) -> ! {
         loop {
             //defmt::println!("idle");
+            core::hint::spin_loop();
         }
     }
 
-    #[task(binds=ADC1_2, priority=5)]//, local=[adc1, adc2, encoder])]
-    fn adc_1_2(_cx: adc_1_2::Context) {
+    /// Current samples and protection.
+    #[task(binds=ADC1_2, priority=5, local=[adcs, cordic, offsets, phase_sense, pwm, calibrated, shunt_plan],
+           shared=[trip, reset, temperature, i_abc])]//, encoder])]
+    fn adc_1_2(mut cx: adc_1_2::Context) {
+        let adcs = cx.local.adcs;
+
+        // Watchdogs first, the outputs go off before anything else. At the
+        // top priority MOE clears a few dozen cycles after the offending
+        // conversion, well under 1us at 170MHz by instruction count, though
+        // not measured on the board. The currents are sampled once a period,
+        // so an overcurrent can take up to 100us more to be seen at all.
+        //
+        // A trip latches: the watchdog that fired is left disarmed and the
+        // outputs off until `reset_fault` asks for both back.
+        let event = adcs.adc1().take_watchdog_event().or_else(|| adcs.adc2().take_watchdog_event());
+        if let Some(event) = event {
+            cx.local.pwm.motor_off();
+            cx.shared.trip.lock(|trip| {
+                if trip.is_none() {
+                    *trip = Some(event);
+                    defmt::println!("Watchdog trip: {}", defmt::Debug2Format(&event));
+                }
+            });
+        }
+        let temp_fault = cx.shared.temperature.lock(|t| t.fault());
+        if let Some(fault) = temp_fault {
+            if cx.local.pwm.is_motor_on() {
+                cx.local.pwm.motor_off();
+                defmt::println!("Temperature fault: {}", defmt::Debug2Format(&fault));
+            }
+        }
+        if cx.shared.reset.lock(core::mem::take) {
+            rearm_watchdogs(adcs);
+            cx.shared.trip.lock(|trip| *trip = None);
+            // A fault still present trips again on the next conversion.
+            if temp_fault.is_none() && *cx.local.calibrated {
+                cx.local.pwm.motor_on();
+                defmt::println!("Fault reset");
+            }
+        }
+
+        #[cfg(not(feature = "single-shunt"))]
+        let currents = adcs.take_currents();
+        #[cfg(feature = "single-shunt")]
+        let currents = adcs.take_shunt_currents();
+        let Some(currents) = currents else {
+            return;
+        };
+        if currents.missed > 0 {
+            defmt::println!("Missed {} current samples before period {}", currents.missed, currents.period);
+        }
+        let amps = currents.amps(cx.local.phase_sense, cx.local.offsets.offsets());
+        #[cfg(not(feature = "single-shunt"))]
+        cx.shared.i_abc.lock(|i| *i = CurrentSense::BOARD.reconstruct(amps));
+        // The pair was taken under the previous plan, which stays in force
+        // through dropped pairs as nothing else moves the compare values.
+        // The next period is planned around the duties now in TIM1.
+        #[cfg(feature = "single-shunt")]
+        if let Some(plan) = cx.local.shunt_plan.replace(apply_shunt_plan(cx.local.pwm)) {
+            cx.shared.i_abc.lock(|i| *i = plan.reconstruct(amps));
+        }
+        let raw = currents.raw;
+
+        // Nothing drives the motor yet, so with the outputs on the duties
+        // stay at 50% and the low side samples see no current. A current
+        // loop must pause tracking while it drives.
+        #[cfg(not(feature = "single-shunt"))]
+        let track_offsets = true;
+        // With a single shunt the opened up sample windows carry ripple
+        // current whenever the outputs are on.
+        #[cfg(feature = "single-shunt")]
+        let track_offsets = !cx.local.pwm.is_motor_on();
+        if track_offsets {
+            let result = cx.local.offsets.update(raw);
+            if let Err(fault) = result {
+                if cx.local.pwm.is_motor_on() {
+                    cx.local.pwm.motor_off();
+                    defmt::println!("Current offset fault: {}", defmt::Debug2Format(&fault));
+                }
+            }
+        }
+
         //defmt::println!("inj: {}, {}", cx.local.adc1.get_inj_data() - cx.shared.zero1, cx.local.adc2.get_inj_data() - cx.shared.zero2);
         //defmt::println!("inj: {}, {}", cx.shared.zero1, cx.shared.zero2);
         //if cx.local.adc1.read_jeos() {
@@ -123,15 +535,36 @@ mod app {
         //}
     }
 
-    #[task(binds=DMA1_CH1, priority=4)]//, local=[adc1_dma])]
-    fn dma1_ch1(_cx: dma1_ch1::Context) {
-        //cx.local.adc1_dma.clear_tcif();
+    /// Clear a latched watchdog trip, re-arming the watchdogs and turning
+    /// the outputs back on unless the temperature is faulted or the boot
+    /// offset calibration failed. For whatever acknowledges faults; nothing
+    /// spawns it on its own.
+    #[task(priority=1, shared=[reset])]
+    fn reset_fault(mut cx: reset_fault::Context) {
+        cx.shared.reset.lock(|reset| *reset = true);
     }
 
-    #[task(binds=DMA1_CH2, priority=3)]//, local=[adc2_dma])]
-    fn dma1_ch2(_cx: dma1_ch2::Context) {
-        //cx.local.adc2_dma.clear_tcif();
-        //    defmt::println!("ADC2 regular channels: {}", unsafe { ADC1BUF});
+    /// ADC1 regular sequence: the power stage thermistor and the internal
+    /// temperature sensor.
+    #[task(binds=DMA1_CH1, priority=4, local=[adc1_dma], shared=[temperature])]
+    fn dma1_ch1(mut cx: dma1_ch1::Context) {
+        if let Some(ranks) = cx.local.adc1_dma.take_rank_averages::<REG_SEQUENCE_LEN>() {
+            let (ntc, vts) = ranks.split_at(REG_SEQUENCE_LEN - 1);
+            let ntc = ntc.iter().sum::<f32>() / ntc.len() as f32;
+            cx.shared.temperature.lock(|t| {
+                t.update_power_stage(ntc);
+                t.update_mcu(vts[0]);
+            });
+        }
+    }
+
+    /// ADC2 regular sequence: VM.
+    #[task(binds=DMA1_CH2, priority=3, local=[adc2_dma, bus, sense], shared=[v_bus])]
+    fn dma1_ch2(mut cx: dma1_ch2::Context) {
+        if let Some(counts) = cx.local.adc2_dma.take_average() {
+            let v_bus = cx.local.bus.update(cx.local.sense.bus_volts(counts));
+            cx.shared.v_bus.lock(|v| *v = v_bus);
+        }
     }
 
     //#[task(binds=TIM1_UP_TIM16, priority=3, local=[tim1])]
@@ -0,0 +1,323 @@
+//! Conversion of ADC readings to engineering units.
+//!
+//! Readings are ratiometric to VDDA, which is measured against VREFINT at
+//! boot, so amps and volts stay right on a board whose 3.3 V rail is off.
+//! The thermistor reading is a ratio of resistors and needs no VDDA at all.
+//!
+//! Channels sampled through the hardware oversampler read to a larger full
+//! scale than a plain 12-bit conversion, so every conversion takes the full
+//! scale of the channel it reads.
+
+pub mod offset;
+pub mod temperature;
+
+/// Full-scale reading of a right-aligned 12-bit conversion.
+pub const ADC_MAX_COUNTS: f32 = 4095.0;
+
+/// Zero of the Celsius scale in kelvin.
+const ZERO_CELSIUS: f32 = 273.15;
+
+/// Full-scale reading after summing `2^ratio_log2` conversions and shifting
+/// the sum right by `shift` bits.
+pub const fn oversampled_full_scale(ratio_log2: u32, shift: u32) -> f32 {
+    ADC_MAX_COUNTS * (1u32 << ratio_log2) as f32 / (1u32 << shift) as f32
+}
+
+/// Pin voltage of a reading with a full scale of `full_scale` counts and the
+/// ADC supply at `vdda` volts.
+pub fn counts_to_volts(counts: f32, full_scale: f32, vdda: f32) -> f32 {
+    counts * vdda / full_scale
+}
+
+/// NTC thermistor from the ADC pin to ground, pulled up to VDDA.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ntc {
+    /// Resistance at 25 °C in ohms.
+    pub r25: f32,
+    /// Beta coefficient in kelvin.
+    pub beta: f32,
+    /// Pull-up resistance in ohms.
+    pub pullup: f32,
+}
+
+impl Ntc {
+    /// Thermistor resistance for a reading with a full scale of `full_scale`
+    /// counts. Readings at the rails are limited to one count in, so an open
+    /// or shorted sensor gives an extreme but finite value.
+    pub fn resistance(&self, counts: f32, full_scale: f32) -> f32 {
+        let x = counts.clamp(1.0, full_scale - 1.0) / full_scale;
+        self.pullup * x / (1.0 - x)
+    }
+
+    /// Temperature in °C for a reading, from the beta equation.
+    pub fn celsius(&self, counts: f32, full_scale: f32) -> f32 {
+        let t25 = 25.0 + ZERO_CELSIUS;
+        let inv_t = 1.0 / t25 + libm::logf(self.resistance(counts, full_scale) / self.r25) / self.beta;
+        1.0 / inv_t - ZERO_CELSIUS
+    }
+}
+
+/// Analog front end parameters of a board.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoardParams {
+    /// Current shunt resistance in ohms.
+    pub shunt: f32,
+    /// Current amplifier voltage gain.
+    pub amp_gain: f32,
+    /// Ratio of bus voltage to ADC pin voltage of the VM divider.
+    pub vm_divider: f32,
+    /// Power stage thermistor on PA2.
+    pub ntc: Ntc,
+}
+
+impl BoardParams {
+    /// Shunts amplified by the OPAMP1/OPAMP2 PGAs at x8, VM through a 20:1
+    /// divider, 10k B3380 NTC with a 10k pull-up.
+    pub const BOARD: Self = Self {
+        shunt: 0.01,
+        amp_gain: 8.0,
+        vm_divider: 20.0,
+        ntc: Ntc { r25: 10_000.0, beta: 3380.0, pullup: 10_000.0 },
+    };
+}
+
+/// Full-scale readings of each measured channel, in counts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Resolution {
+    /// Phase current channels.
+    pub current: f32,
+    /// Bus voltage divider.
+    pub vm: f32,
+    /// Power stage thermistor.
+    pub ntc: f32,
+    /// Internal temperature sensor.
+    pub vts: f32,
+}
+
+impl Resolution {
+    /// Plain 12-bit conversions on every channel.
+    pub const NATIVE: Self = Self {
+        current: ADC_MAX_COUNTS,
+        vm: ADC_MAX_COUNTS,
+        ntc: ADC_MAX_COUNTS,
+        vts: ADC_MAX_COUNTS,
+    };
+
+    /// Bits in a phase current reading, for `fixed::adc_to_q31`.
+    pub fn current_bits(&self) -> u32 {
+        (self.current as u32 + 1).next_power_of_two().trailing_zeros()
+    }
+}
+
+/// Calibrated measurements for a board, at a measured VDDA.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sense {
+    params: BoardParams,
+    resolution: Resolution,
+    vdda: f32,
+    amps_per_count: f32,
+}
+
+impl Sense {
+    pub fn new(params: BoardParams, resolution: Resolution, vdda: f32) -> Self {
+        Self {
+            params,
+            resolution,
+            vdda,
+            amps_per_count: counts_to_volts(1.0, resolution.current, vdda) / (params.amp_gain * params.shunt),
+        }
+    }
+
+    pub fn params(&self) -> &BoardParams {
+        &self.params
+    }
+
+    pub fn resolution(&self) -> &Resolution {
+        &self.resolution
+    }
+
+    pub fn vdda(&self) -> f32 {
+        self.vdda
+    }
+
+    /// Amps per ADC count of a current reading.
+    pub fn amps_per_count(&self) -> f32 {
+        self.amps_per_count
+    }
+
+    /// Shunt current for a reading, given the reading at zero current.
+    pub fn amps(&self, counts: f32, offset: f32) -> f32 {
+        (counts - offset) * self.amps_per_count
+    }
+
+    /// Bus voltage for a VM reading.
+    pub fn bus_volts(&self, counts: f32) -> f32 {
+        counts_to_volts(counts, self.resolution.vm, self.vdda) * self.params.vm_divider
+    }
+
+    /// VM reading for a bus voltage.
+    pub fn bus_volts_to_counts(&self, volts: f32) -> f32 {
+        volts / self.params.vm_divider * self.resolution.vm / self.vdda
+    }
+
+    /// Current readings from `-amps` to `amps` about `offset`, as a low and
+    /// high watchdog threshold within the channel's range.
+    pub fn current_window(&self, amps: f32, offset: f32) -> (f32, f32) {
+        let span = amps / self.amps_per_count;
+        ((offset - span).max(0.0), (offset + span).min(self.resolution.current))
+    }
+
+    /// VM readings from `low` to `high` volts, as a low and high watchdog
+    /// threshold within the channel's range.
+    pub fn bus_volts_window(&self, low: f32, high: f32) -> (f32, f32) {
+        (self.bus_volts_to_counts(low).max(0.0), self.bus_volts_to_counts(high).min(self.resolution.vm))
+    }
+
+    /// Power stage temperature in °C for a thermistor reading.
+    pub fn celsius(&self, counts: f32) -> f32 {
+        self.params.ntc.celsius(counts, self.resolution.ntc)
+    }
+}
+
+/// PWM period count kept from a free-running cycle counter read once per
+/// sample, so periods that went by without a sample are noticed.
+///
+/// Periods are timestamped on a fixed grid, the counter value at the first
+/// sample plus whole periods, so the timestamps are as evenly spaced as the
+/// PWM triggers rather than jittering with the interrupt latency.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PeriodCounter {
+    period_cycles: u32,
+    nominal: Option<u32>,
+    period: u32,
+}
+
+impl PeriodCounter {
+    /// For a PWM period of `period_cycles` counter cycles.
+    pub const fn new(period_cycles: u32) -> Self {
+        assert!(period_cycles > 0, "Zero PWM period");
+        Self { period_cycles, nominal: None, period: 0 }
+    }
+
+    /// Record a sample at counter value `now`. Returns the period it belongs
+    /// to and how many periods since the previous sample had none. Latency
+    /// jitter up to half a period is tolerated.
+    pub fn tick(&mut self, now: u32) -> (u32, u32) {
+        let (nominal, elapsed) = match self.nominal {
+            Some(last) => {
+                let elapsed = (now.wrapping_sub(last) + self.period_cycles / 2) / self.period_cycles;
+                // Two samples within half a period are still two periods.
+                let elapsed = elapsed.max(1);
+                (last.wrapping_add(elapsed * self.period_cycles), elapsed)
+            }
+            None => (now, 1),
+        };
+        self.nominal = Some(nominal);
+        self.period = self.period.wrapping_add(elapsed);
+        (self.period, elapsed - 1)
+    }
+
+    /// Counter value on the grid for the latest period, zero before the
+    /// first sample.
+    pub fn cycles(&self) -> u32 {
+        self.nominal.unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32, eps: f32) -> bool {
+        (a - b).abs() < eps
+    }
+
+    #[test]
+    fn current_scale() {
+        let sense = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.3);
+        // 1 A gives 10 mV across the shunt, 80 mV at the ADC.
+        let counts = 0.08 / 3.3 * ADC_MAX_COUNTS;
+        assert!(close(sense.amps(2048.0 + counts, 2048.0), 1.0, 1e-4));
+        assert!(close(sense.amps(2048.0 - counts, 2048.0), -1.0, 1e-4));
+        // Lower VDDA means each count is fewer volts.
+        let low = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.0);
+        assert!(low.amps_per_count() < sense.amps_per_count());
+    }
+
+    #[test]
+    fn bus_voltage() {
+        let sense = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.3);
+        assert!(close(sense.bus_volts(ADC_MAX_COUNTS / 2.0), 33.0, 1e-3));
+    }
+
+    #[test]
+    fn watchdog_windows() {
+        let sense = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.3);
+        let (low, high) = sense.current_window(5.0, 2048.0);
+        assert!(close(sense.amps(low, 2048.0), -5.0, 1e-3));
+        assert!(close(sense.amps(high, 2048.0), 5.0, 1e-3));
+        // Beyond the amplifier range the window is the whole channel.
+        assert_eq!(sense.current_window(100.0, 2048.0), (0.0, ADC_MAX_COUNTS));
+
+        let (low, high) = sense.bus_volts_window(6.0, 48.0);
+        assert!(close(sense.bus_volts(low), 6.0, 1e-3));
+        assert!(close(sense.bus_volts(high), 48.0, 1e-3));
+        assert_eq!(sense.bus_volts_window(-1.0, 100.0), (0.0, ADC_MAX_COUNTS));
+    }
+
+    #[test]
+    fn period_counter() {
+        let mut counter = PeriodCounter::new(17000);
+        let mut now = u32::MAX - 20_000;
+        assert_eq!(counter.tick(now), (1, 0));
+        let start = now;
+        // Late by a third of a period, across the counter wrapping.
+        now = now.wrapping_add(17000 + 5000);
+        assert_eq!(counter.tick(now), (2, 0));
+        // The timestamp stays on the grid.
+        assert_eq!(counter.cycles(), start.wrapping_add(17000));
+        now = now.wrapping_add(17000 - 5000);
+        assert_eq!(counter.tick(now), (3, 0));
+        // Two periods went by unsampled.
+        now = now.wrapping_add(3 * 17000 + 300);
+        assert_eq!(counter.tick(now), (6, 2));
+        assert_eq!(counter.cycles(), start.wrapping_add(5 * 17000));
+        // Early by almost half a period still lands on the next one.
+        now = start.wrapping_add(6 * 17000 - 8000);
+        assert_eq!(counter.tick(now), (7, 0));
+        assert_eq!(counter.cycles(), start.wrapping_add(6 * 17000));
+    }
+
+    #[test]
+    fn oversampled_channels() {
+        // 16 conversions summed without a shift give a 16-bit result.
+        let full_scale = oversampled_full_scale(4, 0);
+        assert_eq!(full_scale, 65520.0);
+        assert_eq!(oversampled_full_scale(4, 4), ADC_MAX_COUNTS);
+        let resolution = Resolution { vm: full_scale, ntc: full_scale, ..Resolution::NATIVE };
+        let sense = Sense::new(BoardParams::BOARD, resolution, 3.3);
+        let native = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.3);
+        assert!(close(sense.bus_volts(full_scale / 2.0), native.bus_volts(ADC_MAX_COUNTS / 2.0), 1e-3));
+        assert!(close(sense.celsius(full_scale / 3.0), native.celsius(ADC_MAX_COUNTS / 3.0), 1e-3));
+        assert_eq!(sense.amps_per_count(), native.amps_per_count());
+        assert_eq!(Resolution::NATIVE.current_bits(), 12);
+        assert_eq!(Resolution { current: oversampled_full_scale(2, 0), ..Resolution::NATIVE }.current_bits(), 14);
+        assert_eq!(Resolution { current: full_scale, ..Resolution::NATIVE }.current_bits(), 16);
+    }
+
+    #[test]
+    fn thermistor() {
+        let ntc = BoardParams::BOARD.ntc;
+        // Equal to the pull-up at 25 °C gives half scale.
+        assert!(close(ntc.celsius(ADC_MAX_COUNTS / 2.0, ADC_MAX_COUNTS), 25.0, 1e-3));
+        // Resistance falls as temperature rises.
+        assert!(ntc.celsius(1000.0, ADC_MAX_COUNTS) > 25.0 && ntc.celsius(3000.0, ADC_MAX_COUNTS) < 25.0);
+        // About 1.25 k at 85 °C for B3380.
+        let r85 = ntc.r25 * libm::expf(ntc.beta * (1.0 / 358.15 - 1.0 / 298.15));
+        let counts = r85 / (r85 + ntc.pullup) * ADC_MAX_COUNTS;
+        assert!(close(ntc.celsius(counts, ADC_MAX_COUNTS), 85.0, 1e-2));
+        // Open and shorted sensors stay finite.
+        assert!(ntc.celsius(ADC_MAX_COUNTS, ADC_MAX_COUNTS).is_finite());
+        assert!(ntc.celsius(0.0, ADC_MAX_COUNTS).is_finite());
+    }
+}
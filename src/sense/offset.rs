@@ -0,0 +1,223 @@
+//! Current amplifier offset calibration.
+//!
+//! With no phase current the amplifier outputs sit near mid-rail, but the
+//! exact zero differs between boards and moves with temperature. Any error
+//! becomes a DC current in the stationary frame, which the current loop
+//! turns into torque ripple at the electrical frequency. The offset is
+//! measured at boot with the outputs off and then tracked slowly whenever
+//! the motor is not driven.
+
+use super::ADC_MAX_COUNTS;
+
+/// Number of current channels, one per shunt.
+const CHANNELS: usize = 2;
+
+/// Acceptance limits for offset readings, in ADC counts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OffsetLimits {
+    /// Full-scale reading of the current channels.
+    pub full_scale: f32,
+    /// Largest distance of an offset from mid-rail.
+    pub max_deviation: f32,
+    /// Samples further than this from the median are outliers.
+    pub outlier: f32,
+    /// Largest fraction of outliers in a calibration.
+    pub max_outliers: f32,
+}
+
+impl OffsetLimits {
+    /// 150 counts is about 120 mV at the ADC, well beyond amplifier and bias
+    /// resistor tolerances. For 12-bit readings.
+    pub const DEFAULT: Self = Self {
+        full_scale: ADC_MAX_COUNTS,
+        max_deviation: 150.0,
+        outlier: 40.0,
+        max_outliers: 0.1,
+    };
+
+    /// The same limits at the pin for readings with a full scale of
+    /// `full_scale` counts.
+    pub fn scaled(self, full_scale: f32) -> Self {
+        let k = full_scale / self.full_scale;
+        Self {
+            full_scale,
+            max_deviation: self.max_deviation * k,
+            outlier: self.outlier * k,
+            max_outliers: self.max_outliers,
+        }
+    }
+
+    /// Nominal zero-current reading.
+    pub fn mid_rail(&self) -> f32 {
+        self.full_scale / 2.0
+    }
+
+    fn check(&self, channel: usize, offset: f32) -> Result<f32, OffsetFault> {
+        if (offset - self.mid_rail()).abs() <= self.max_deviation {
+            Ok(offset)
+        } else {
+            Err(OffsetFault::OutOfRange(channel))
+        }
+    }
+}
+
+/// Reason an offset was rejected, with the channel index.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OffsetFault {
+    /// The offset is too far from mid-rail, e.g. a dead amplifier or a
+    /// current flowing during calibration.
+    OutOfRange(usize),
+    /// Too many samples were outliers.
+    Noisy(usize),
+}
+
+/// Boot-time offset calibration over `N` samples per channel.
+#[derive(Clone, Debug)]
+pub struct OffsetCalibration<const N: usize> {
+    samples: [[u16; N]; CHANNELS],
+    len: usize,
+    limits: OffsetLimits,
+}
+
+impl<const N: usize> OffsetCalibration<N> {
+    pub fn new(limits: OffsetLimits) -> Self {
+        Self { samples: [[0; N]; CHANNELS], len: 0, limits }
+    }
+
+    /// Add one reading of each channel. Returns true once all `N` samples
+    /// have been collected; further readings are ignored.
+    pub fn push(&mut self, raw: [u16; CHANNELS]) -> bool {
+        if self.len < N {
+            for (samples, raw) in self.samples.iter_mut().zip(raw) {
+                samples[self.len] = raw;
+            }
+            self.len += 1;
+        }
+        self.len == N
+    }
+
+    /// Offsets from the samples collected so far. Samples far from the
+    /// median are dropped and the rest averaged.
+    pub fn finish(&mut self) -> Result<[f32; CHANNELS], OffsetFault> {
+        let mut offsets = [0.0; CHANNELS];
+        for (channel, samples) in self.samples.iter_mut().enumerate() {
+            let samples = &mut samples[..self.len];
+            samples.sort_unstable();
+            let median = match samples.get(samples.len() / 2) {
+                Some(median) => *median as f32,
+                None => return Err(OffsetFault::Noisy(channel)),
+            };
+
+            let (sum, count) = samples.iter()
+                .map(|s| *s as f32)
+                .filter(|s| (s - median).abs() <= self.limits.outlier)
+                .fold((0.0, 0usize), |(sum, count), s| (sum + s, count + 1));
+            let outliers = (samples.len() - count) as f32 / samples.len() as f32;
+            if outliers > self.limits.max_outliers {
+                return Err(OffsetFault::Noisy(channel));
+            }
+            offsets[channel] = self.limits.check(channel, sum / count as f32)?;
+        }
+        Ok(offsets)
+    }
+}
+
+/// Slow tracking of the offsets while no current flows.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OffsetTracker {
+    offsets: [f32; CHANNELS],
+    alpha: f32,
+    limits: OffsetLimits,
+}
+
+impl OffsetTracker {
+    /// Start from calibrated `offsets`, following drift with a time constant
+    /// of `tau` seconds when updated every `ts` seconds.
+    pub fn new(offsets: [f32; CHANNELS], tau: f32, ts: f32, limits: OffsetLimits) -> Self {
+        Self { offsets, alpha: ts / (ts + tau), limits }
+    }
+
+    pub fn offsets(&self) -> [f32; CHANNELS] {
+        self.offsets
+    }
+
+    /// Add a reading taken while the motor is not driven. Outlying readings
+    /// are ignored; an offset drifting out of range is a fault.
+    pub fn update(&mut self, raw: [u16; CHANNELS]) -> Result<(), OffsetFault> {
+        for (channel, (offset, raw)) in self.offsets.iter_mut().zip(raw).enumerate() {
+            let error = raw as f32 - *offset;
+            if error.abs() <= self.limits.outlier {
+                *offset += self.alpha * error;
+            }
+            self.limits.check(channel, *offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_outliers() {
+        let mut cal = OffsetCalibration::<100>::new(OffsetLimits::DEFAULT);
+        for i in 0..100 {
+            let spike = if i % 20 == 0 { 900 } else { 0 };
+            let done = cal.push([2040 + (i % 3) as u16 + spike, 2060]);
+            assert_eq!(done, i == 99);
+        }
+        let offsets = cal.finish().unwrap();
+        assert!((offsets[0] - 2041.0).abs() < 0.1, "{:?}", offsets);
+        assert_eq!(offsets[1], 2060.0);
+    }
+
+    #[test]
+    fn out_of_range_and_noisy() {
+        let mut cal = OffsetCalibration::<10>::new(OffsetLimits::DEFAULT);
+        for _ in 0..10 {
+            cal.push([2048, 1500]);
+        }
+        assert_eq!(cal.finish(), Err(OffsetFault::OutOfRange(1)));
+
+        let mut cal = OffsetCalibration::<10>::new(OffsetLimits::DEFAULT);
+        for i in 0..10 {
+            cal.push([if i % 2 == 0 { 1900 } else { 2100 }, 2048]);
+        }
+        assert_eq!(cal.finish(), Err(OffsetFault::Noisy(0)));
+
+        let mut empty = OffsetCalibration::<10>::new(OffsetLimits::DEFAULT);
+        assert!(empty.finish().is_err());
+    }
+
+    #[test]
+    fn oversampled_readings() {
+        // Four times the 12-bit full scale, as with 4x oversampling unshifted.
+        let limits = OffsetLimits::DEFAULT.scaled(4.0 * ADC_MAX_COUNTS);
+        assert_eq!(limits.mid_rail(), 2.0 * ADC_MAX_COUNTS);
+        let mut cal = OffsetCalibration::<10>::new(limits);
+        for _ in 0..10 {
+            cal.push([8190 + 400, 8190 - 700]);
+        }
+        assert_eq!(cal.finish(), Err(OffsetFault::OutOfRange(1)));
+    }
+
+    #[test]
+    fn tracks_drift() {
+        let mut tracker = OffsetTracker::new([2048.0, 2048.0], 1.0, 0.01, OffsetLimits::DEFAULT);
+        for _ in 0..1000 {
+            // A spike on channel 1 is ignored.
+            tracker.update([2058, 4000]).unwrap();
+        }
+        let offsets = tracker.offsets();
+        assert!((offsets[0] - 2058.0).abs() < 0.1, "{:?}", offsets);
+        assert_eq!(offsets[1], 2048.0);
+    }
+
+    #[test]
+    fn drift_out_of_range() {
+        let mut tracker = OffsetTracker::new([2190.0, 2048.0], 0.1, 0.01, OffsetLimits::DEFAULT);
+        let result = (0..100).try_for_each(|_| tracker.update([2220, 2048]));
+        assert_eq!(result, Err(OffsetFault::OutOfRange(0)));
+    }
+}
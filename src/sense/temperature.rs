@@ -0,0 +1,255 @@
+//! Power stage and MCU temperature.
+//!
+//! The power stage thermistor and the MCU's internal sensor are filtered
+//! separately, each with its own limits. As either heats up the allowed
+//! phase current is derated, and past its trip point the temperature is a
+//! fault until it has cooled below a lower clear point.
+
+use super::{Ntc, Sense};
+
+/// Factory calibration of the internal temperature sensor, readings at
+/// 30 °C and 130 °C taken at 12 bits with VDDA at 3.0 V.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InternalSensor {
+    pub cal1: u16,
+    pub cal2: u16,
+}
+
+impl InternalSensor {
+    const CAL1_CELSIUS: f32 = 30.0;
+    const CAL2_CELSIUS: f32 = 130.0;
+    const CAL_VDDA: f32 = 3.0;
+
+    /// Temperature in °C for a reading with a full scale of `full_scale`
+    /// counts, with the ADC supply at `vdda` volts.
+    pub fn celsius(&self, counts: f32, full_scale: f32, vdda: f32) -> f32 {
+        // The same pin voltage as a reading under calibration conditions.
+        let counts = counts * super::ADC_MAX_COUNTS / full_scale * vdda / Self::CAL_VDDA;
+        let slope = (Self::CAL2_CELSIUS - Self::CAL1_CELSIUS) / (self.cal2 as f32 - self.cal1 as f32);
+        Self::CAL1_CELSIUS + (counts - self.cal1 as f32) * slope
+    }
+}
+
+/// Temperature limits of one sensor, in °C.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TempLimits {
+    /// Full current up to here.
+    pub derate_start: f32,
+    /// Current falls linearly to zero here.
+    pub derate_end: f32,
+    /// Overtemperature fault above here.
+    pub trip: f32,
+    /// The fault clears below here.
+    pub clear: f32,
+}
+
+impl TempLimits {
+    /// FETs on the power stage, rated to 150 °C junction.
+    pub const POWER_STAGE: Self = Self { derate_start: 80.0, derate_end: 110.0, trip: 115.0, clear: 95.0 };
+    /// STM32G431, rated to 125 °C ambient.
+    pub const MCU: Self = Self { derate_start: 100.0, derate_end: 120.0, trip: 125.0, clear: 105.0 };
+
+    /// Fraction of the full current allowed at `celsius`.
+    pub fn derating(&self, celsius: f32) -> f32 {
+        let span = self.derate_end - self.derate_start;
+        (1.0 - (celsius - self.derate_start) / span).clamp(0.0, 1.0)
+    }
+}
+
+/// Reason the temperature subsystem stopped the motor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TempFault {
+    /// Power stage over its trip temperature.
+    PowerStage,
+    /// MCU over its trip temperature.
+    Mcu,
+    /// The thermistor reads at the rail, as with it unplugged. It would
+    /// otherwise read as very cold.
+    NtcOpen,
+}
+
+/// Filtered temperature of one sensor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Channel {
+    limits: TempLimits,
+    celsius: f32,
+    valid: bool,
+    tripped: bool,
+}
+
+impl Channel {
+    fn new(limits: TempLimits) -> Self {
+        Self { limits, celsius: 0.0, valid: false, tripped: false }
+    }
+
+    /// The first sample initialises the filter.
+    fn update(&mut self, celsius: f32, alpha: f32) {
+        if self.valid {
+            self.celsius += alpha * (celsius - self.celsius);
+        } else {
+            self.celsius = celsius;
+            self.valid = true;
+        }
+        if self.celsius > self.limits.trip {
+            self.tripped = true;
+        } else if self.celsius < self.limits.clear {
+            self.tripped = false;
+        }
+    }
+
+    /// Full current until the first sample.
+    fn derating(&self) -> f32 {
+        match (self.valid, self.tripped) {
+            (false, _) => 1.0,
+            (true, true) => 0.0,
+            (true, false) => self.limits.derating(self.celsius),
+        }
+    }
+}
+
+/// Power stage and MCU temperatures, with derating and overtemperature.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Temperature {
+    ntc: Ntc,
+    ntc_full_scale: f32,
+    sensor: InternalSensor,
+    sensor_full_scale: f32,
+    vdda: f32,
+    alpha: f32,
+    ntc_open: bool,
+    power_stage: Channel,
+    mcu: Channel,
+}
+
+impl Temperature {
+    /// Convert readings as `sense` does, filtering with a time constant of
+    /// `tau` seconds when updated every `ts` seconds.
+    pub fn new(sense: &Sense, sensor: InternalSensor, power_stage: TempLimits, mcu: TempLimits,
+               tau: f32, ts: f32) -> Self {
+        Self {
+            ntc: sense.params().ntc,
+            ntc_full_scale: sense.resolution().ntc,
+            sensor,
+            sensor_full_scale: sense.resolution().vts,
+            vdda: sense.vdda(),
+            alpha: ts / (ts + tau),
+            ntc_open: false,
+            power_stage: Channel::new(power_stage),
+            mcu: Channel::new(mcu),
+        }
+    }
+
+    /// Add a power stage thermistor reading.
+    pub fn update_power_stage(&mut self, counts: f32) {
+        // Within 1% of the rail is an open circuit rather than frost.
+        self.ntc_open = counts > 0.99 * self.ntc_full_scale;
+        self.power_stage.update(self.ntc.celsius(counts, self.ntc_full_scale), self.alpha);
+    }
+
+    /// Add an internal temperature sensor reading.
+    pub fn update_mcu(&mut self, counts: f32) {
+        self.mcu.update(self.sensor.celsius(counts, self.sensor_full_scale, self.vdda), self.alpha);
+    }
+
+    /// Filtered power stage temperature in °C.
+    pub fn power_stage(&self) -> f32 {
+        self.power_stage.celsius
+    }
+
+    /// Filtered MCU temperature in °C.
+    pub fn mcu(&self) -> f32 {
+        self.mcu.celsius
+    }
+
+    /// Phase current allowed out of `max` amps, zero while faulted.
+    ///
+    /// A limit for the current reference. It falls to zero at the end of the
+    /// derating, so it is no overcurrent threshold.
+    pub fn current_limit(&self, max: f32) -> f32 {
+        if self.fault().is_some() {
+            return 0.0;
+        }
+        max * self.power_stage.derating().min(self.mcu.derating())
+    }
+
+    pub fn fault(&self) -> Option<TempFault> {
+        if self.ntc_open {
+            Some(TempFault::NtcOpen)
+        } else if self.power_stage.tripped {
+            Some(TempFault::PowerStage)
+        } else if self.mcu.tripped {
+            Some(TempFault::Mcu)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sense::{BoardParams, Resolution, ADC_MAX_COUNTS};
+
+    const SENSOR: InternalSensor = InternalSensor { cal1: 1000, cal2: 1300 };
+
+    /// Thermistor reading at `celsius`, from the beta equation.
+    fn ntc_counts(celsius: f32) -> f32 {
+        let ntc = BoardParams::BOARD.ntc;
+        let r = ntc.r25 * libm::expf(ntc.beta * (1.0 / (celsius + 273.15) - 1.0 / 298.15));
+        r / (r + ntc.pullup) * ADC_MAX_COUNTS
+    }
+
+    fn temperature() -> Temperature {
+        let sense = Sense::new(BoardParams::BOARD, Resolution::NATIVE, 3.3);
+        // No filtering, so each update lands.
+        Temperature::new(&sense, SENSOR, TempLimits::POWER_STAGE, TempLimits::MCU, 0.0, 1.0)
+    }
+
+    #[test]
+    fn internal_sensor() {
+        assert!((SENSOR.celsius(1150.0, ADC_MAX_COUNTS, 3.0) - 80.0).abs() < 1e-3);
+        // A higher VDDA gives fewer counts for the same voltage.
+        assert!((SENSOR.celsius(1150.0 * 3.0 / 3.3, ADC_MAX_COUNTS, 3.3) - 80.0).abs() < 1e-3);
+        // 4x oversampled without a shift.
+        assert!((SENSOR.celsius(4600.0, 4.0 * ADC_MAX_COUNTS, 3.0) - 80.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn derating() {
+        let limits = TempLimits::POWER_STAGE;
+        assert_eq!(limits.derating(25.0), 1.0);
+        assert!((limits.derating(95.0) - 0.5).abs() < 1e-6);
+        assert_eq!(limits.derating(150.0), 0.0);
+
+        let mut temp = temperature();
+        assert_eq!(temp.current_limit(20.0), 20.0);
+        temp.update_power_stage(ntc_counts(95.0));
+        assert!((temp.power_stage() - 95.0).abs() < 0.01);
+        assert!((temp.current_limit(20.0) - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn overtemperature_hysteresis() {
+        let mut temp = temperature();
+        temp.update_power_stage(ntc_counts(118.0));
+        assert_eq!(temp.fault(), Some(TempFault::PowerStage));
+        assert_eq!(temp.current_limit(20.0), 0.0);
+        // Still faulted between the clear and trip points.
+        temp.update_power_stage(ntc_counts(100.0));
+        assert_eq!(temp.fault(), Some(TempFault::PowerStage));
+        temp.update_power_stage(ntc_counts(90.0));
+        assert_eq!(temp.fault(), None);
+
+        temp.update_mcu(1300.0 * 3.0 / 3.3);
+        assert_eq!(temp.fault(), Some(TempFault::Mcu));
+    }
+
+    #[test]
+    fn open_thermistor() {
+        let mut temp = temperature();
+        temp.update_power_stage(ADC_MAX_COUNTS);
+        assert_eq!(temp.fault(), Some(TempFault::NtcOpen));
+        temp.update_power_stage(ntc_counts(25.0));
+        assert_eq!(temp.fault(), None);
+    }
+}
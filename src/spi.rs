@@ -0,0 +1,107 @@
+#![allow(dead_code)]
+
+//! SPI1 in half duplex on one data line, for the MA734 encoder.
+//!
+//! The encoder's data shares PB5 with no separate MISO, so SPI1 runs with
+//! BIDIMODE set, which the HAL's SPI doesn't support. Only reads are made:
+//! the line is never driven, so the encoder sees an all zero command, which
+//! reads the angle.
+
+use core::ptr;
+
+use stm32g4xx_hal::gpio::gpiob::{PB3, PB5};
+use stm32g4xx_hal::gpio::Alternate;
+use stm32g4xx_hal::hal::blocking::spi::Transfer;
+use stm32g4xx_hal::stm32::SPI1;
+
+/// AF5 routes SPI1 to PB3 and PB5.
+pub type Sck = PB3<Alternate<5>>;
+pub type Data = PB5<Alternate<5>>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Overrun,
+}
+
+pub struct HalfDuplex {
+    spi: SPI1,
+    /// Baud rate divider exponent, the SPI clock is fPCLK / 2^(br + 1).
+    br: u8,
+}
+
+impl HalfDuplex {
+    pub fn new(spi: SPI1, _pins: (Sck, Data)) -> Self {
+        Self { spi, br: 0 }
+    }
+
+    /// Master in SPI mode 0 with 8 bit frames, receiving. The clock is
+    /// fPCLK / 2^(br + 1).
+    pub fn init(&mut self, br: u8) {
+        self.br = br & 0b111;
+        self.spi.cr1.write(|w| w.spe().clear_bit());
+        self.spi.cr2.write(|w| unsafe { w.ds().bits(0b0111).frxth().set_bit() });
+        self.spi.cr1.write(|w| unsafe {
+            w.bidimode().set_bit().bidioe().clear_bit()
+                .ssm().set_bit().ssi().set_bit()
+                .br().bits(self.br).mstr().set_bit()
+                .cpol().clear_bit().cpha().clear_bit()
+        });
+    }
+
+    /// Fill `words` with the bytes clocked in.
+    ///
+    /// In receive only mode the clock runs for as long as the SPI is
+    /// enabled, so it's disabled one SPI clock into the last frame, which
+    /// then completes, as the reference manual's receive only procedure.
+    pub fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        let Some(last) = words.len().checked_sub(1) else { return Ok(()) };
+        self.drain();
+        self.spi.cr1.modify(|_, w| w.spe().set_bit());
+        for (i, word) in words.iter_mut().enumerate() {
+            if i == last {
+                self.stop();
+            }
+            *word = self.read_byte()?;
+        }
+        Ok(())
+    }
+
+    /// Disable the SPI one SPI clock after the previous frame landed or the
+    /// SPI was enabled, so the frame then in flight is the last.
+    fn stop(&self) {
+        cortex_m::asm::delay(2 << self.br);
+        self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+    }
+
+    fn read_byte(&self) -> Result<u8, Error> {
+        loop {
+            let sr = self.spi.sr.read();
+            if sr.ovr().bit_is_set() {
+                self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                return Err(Error::Overrun);
+            }
+            if sr.rxne().bit_is_set() {
+                // One byte, the 16 bit DR would pop two from the FIFO.
+                return Ok(unsafe { ptr::read_volatile(&self.spi.dr as *const _ as *const u8) });
+            }
+        }
+    }
+
+    /// Empty the receive FIFO of anything left over, clearing an overrun.
+    fn drain(&self) {
+        while self.spi.sr.read().frlvl().bits() != 0 {
+            unsafe { ptr::read_volatile(&self.spi.dr as *const _ as *const u8) };
+        }
+        let _ = self.spi.sr.read();
+    }
+}
+
+impl Transfer<u8> for HalfDuplex {
+    type Error = Error;
+
+    /// The bytes in `words` aren't sent, see the module doc.
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        self.read(words)?;
+        Ok(words)
+    }
+}
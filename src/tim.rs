@@ -3,7 +3,11 @@
 use stm32g4xx_hal::stm32::TIM1;
 use stm32g4xx_hal::stm32::tim1::ccmr1_output::{OC1M_A, OC2M_A};
 use stm32g4xx_hal::stm32::tim1::ccmr2_output::{OC3M_A, OC4M_A};
-use stm32g4xx_hal::stm32::tim1::ccmr3_output::OC5M_A;
+use stm32g4xx_hal::stm32::tim1::ccmr3_output::{OC5M_A, OC6M_A};
+use ufoc::foc::deadtime::dtg_to_seconds;
+
+/// Dead-time generator setting, 500ns at a 170MHz timer clock.
+const DEAD_TIME_DTG: u8 = 85;
 
 
 /// Generic timer driver.
@@ -30,6 +34,16 @@ impl PwmTim {
         self.tim.bdtr.modify(|_, w| w.moe().set_bit());
     }
 
+    /// Force all outputs to their idle (low) state by clearing MOE.
+    pub fn motor_off(&self) {
+        self.tim.bdtr.modify(|_, w| w.moe().clear_bit());
+    }
+
+    /// Whether the main outputs are enabled.
+    pub fn is_motor_on(&self) -> bool {
+        self.tim.bdtr.read().moe().bit_is_set()
+    }
+
     /// Stop the timer running by clearing the CEN bit.
     pub fn stop(&self) {
         self.tim.cr1.modify(|_, w| w.cen().clear_bit());
@@ -89,7 +103,7 @@ impl PwmTim {
         );
 
         //Enable dead time to 500ns (clock 170Mhz) and make outputs low when MOE is 0
-        self.tim.bdtr.write(|w| w.dtg().variant(85).ossi().set_bit());
+        self.tim.bdtr.write(|w| w.dtg().variant(DEAD_TIME_DTG).ossi().set_bit());
 
         //Setup PWM to 0% and set trigger channels
         self.tim.ccr1().write(|w| w.ccr().variant(0));
@@ -117,4 +131,60 @@ impl PwmTim {
         self.tim.ccr3().write(|w| w.ccr().variant(ch3 * arr / 65536));
     }
 
+    /// Write raw compare values, as produced by the fixed-point modulator.
+    pub fn set_bldc_ccr(&self, ch1: u32, ch2: u32, ch3: u32) {
+        self.tim.ccr1().write(|w| w.ccr().variant(ch1));
+        self.tim.ccr2().write(|w| w.ccr().variant(ch2));
+        self.tim.ccr3().write(|w| w.ccr().variant(ch3));
+    }
+
+    /// Compare values of the three phases.
+    pub fn bldc_ccr(&self) -> [u32; 3] {
+        [
+            self.tim.ccr1().read().ccr().bits(),
+            self.tim.ccr2().read().ccr().bits(),
+            self.tim.ccr3().read().ccr().bits(),
+        ]
+    }
+
+    /// Pulse TRGO2 at two single-shunt sample points per period, both as
+    /// the timer counts up: OC4REF rising, with OC4 in PWM mode 2, then
+    /// OC6REF falling. TRGO stays on OC4REF, rising once per period. Place
+    /// the points with `set_sample_points`.
+    pub fn setup_shunt_triggers(&self) {
+        self.tim.ccmr2_output().modify(|_, w| w.oc4m().variant(OC4M_A::PwmMode2));
+        self.tim.ccmr3_output.modify(|_, w| w.oc6m().variant(OC6M_A::PwmMode1).oc6pe().set_bit());
+        self.tim.cr2.modify(|_, w| w.mms2().variant(0b1101));
+    }
+
+    /// Move the single-shunt sample points, in counts as the timer counts
+    /// up, from the next period. `first` must be below `second`.
+    pub fn set_sample_points(&self, first: u32, second: u32) {
+        self.tim.ccr4().write(|w| w.ccr().variant(first));
+        self.tim.ccr6.write(|w| w.ccr().variant(second));
+    }
+
+    /// Wait until the timer is counting down, between the last sample point
+    /// of one period and the first of the next.
+    pub fn wait_counting_down(&self) {
+        while self.tim.cr1.read().dir().bit_is_clear() {}
+    }
+
+    /// Auto-reload value the compare values are relative to.
+    pub fn arr(&self) -> u32 {
+        self.tim.arr.read().arr().bits()
+    }
+
+    /// Dead time currently inserted on each output edge, in seconds, for a
+    /// timer clock of `tim_clk` Hz.
+    pub fn dead_time(&self, tim_clk: u32) -> f32 {
+        let dts_clk = tim_clk >> self.tim.cr1.read().ckd().bits();
+        dtg_to_seconds(self.tim.bdtr.read().dtg().bits(), dts_clk)
+    }
+
+    /// Center-aligned PWM period in seconds, for a timer clock of `tim_clk` Hz.
+    pub fn pwm_period(&self, tim_clk: u32) -> f32 {
+        2.0 * self.arr() as f32 / tim_clk as f32
+    }
+
 }
\ No newline at end of file